@@ -0,0 +1,251 @@
+use crate::color::{LUMA_MAX, LumaLut, srgb_to_linear};
+use crate::filt::{MilkBuffer, MilkConfig};
+use crate::pipeline::{Effect, Param, Value};
+use rayon::prelude::*;
+
+/// Tone and color corrections, meant to run before the milk mapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    /// Stops of light, applied in linear light
+    pub exposure: f32,
+    /// -1..=1, pivots around middle gray
+    pub contrast: f32,
+    pub gamma: f32,
+    /// 0 is grayscale, 1 unchanged
+    pub saturation: f32,
+    /// White balance, positive is warmer
+    pub temperature: f32,
+    /// White balance, positive is more magenta
+    pub tint: f32,
+    /// Stretches the darkest and brightest pixels to black and white
+    pub auto_levels: bool,
+    /// Spreads brightness evenly over the whole range, wins over `auto_levels`
+    pub equalize: bool,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            contrast: 0.0,
+            gamma: 1.0,
+            saturation: 1.0,
+            temperature: 0.0,
+            tint: 0.0,
+            auto_levels: false,
+            equalize: false,
+        }
+    }
+}
+
+// Share of pixels clipped on each end by auto levels
+const LEVELS_CLIP: f32 = 0.005;
+// Brightness histogram resolution
+const HIST_BINS: usize = 4096;
+
+impl Tone {
+    // Channel gains of the white balance
+    fn balance(&self) -> [f32; 3] {
+        [
+            1.0 + self.temperature * 0.2 + self.tint * 0.1,
+            1.0 - self.tint * 0.2,
+            1.0 - self.temperature * 0.2 + self.tint * 0.1,
+        ]
+    }
+
+    // Per channel map of 16 bit input to 0..=1 output, `range` is stretched to full
+    fn curve(&self, gain: f32, range: (f32, f32)) -> Vec<f32> {
+        let gain = gain * self.exposure.exp2();
+        let contrast = (1.0 + self.contrast).max(0.0);
+        let inv_gamma = 1.0 / self.gamma.max(0.01);
+
+        (0..=u16::MAX)
+            .into_par_iter()
+            .map(|v| {
+                let v = ((v as f32 - range.0) / (range.1 - range.0)).clamp(0.0, 1.0);
+                let lin = srgb_to_linear((v * 65535.0).round() as u16) * gain;
+                let v = linear_encode(lin);
+                let v = ((v - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
+                v.powf(inv_gamma)
+            })
+            .collect()
+    }
+}
+
+fn linear_encode(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+    .clamp(0.0, 1.0)
+}
+
+// Histogram of brightness over `HIST_BINS` bins
+fn histogram(img: &MilkBuffer, luma: &LumaLut) -> Vec<u32> {
+    img.as_raw()
+        .par_chunks_exact(4)
+        .fold(
+            || vec![0u32; HIST_BINS],
+            |mut hist, p| {
+                hist[luma.index(p[0], p[1], p[2]) * HIST_BINS / (LUMA_MAX + 1)] += 1;
+                hist
+            },
+        )
+        .reduce(
+            || vec![0u32; HIST_BINS],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            },
+        )
+}
+
+// Brightness below which `LEVELS_CLIP` of pixels fall, and the same from the top
+fn levels(hist: &[u32]) -> (f32, f32) {
+    let total: u64 = hist.iter().map(|&n| n as u64).sum();
+    let clip = (total as f32 * LEVELS_CLIP) as u64;
+    let bin = |i: usize| (i * (LUMA_MAX + 1) / HIST_BINS) as f32;
+
+    let mut sum = 0;
+    let lo = hist
+        .iter()
+        .position(|&n| {
+            sum += n as u64;
+            sum > clip
+        })
+        .unwrap_or(0);
+    sum = 0;
+    let hi = HIST_BINS
+        - 1
+        - hist
+            .iter()
+            .rev()
+            .position(|&n| {
+                sum += n as u64;
+                sum > clip
+            })
+            .unwrap_or(0);
+
+    if hi <= lo {
+        (0.0, LUMA_MAX as f32)
+    } else {
+        (bin(lo), bin(hi + 1) - 1.0)
+    }
+}
+
+// Per bin gain moving every brightness to its place in the cumulative histogram
+fn equalize(hist: &[u32]) -> Vec<f32> {
+    let total = hist.iter().map(|&n| n as u64).sum::<u64>().max(1) as f32;
+    let mut sum = 0u64;
+    hist.iter()
+        .enumerate()
+        .map(|(i, &n)| {
+            sum += n as u64;
+            let from = (i as f32 + 0.5) / HIST_BINS as f32;
+            let to = (sum as f32 - n as f32 * 0.5) / total;
+            to / from
+        })
+        .collect()
+}
+
+impl Effect for Tone {
+    fn name(&self) -> &'static str {
+        "tone"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        if *self == Self::default() {
+            return;
+        }
+
+        let luma = conf.luma_lut();
+        let hist = (self.auto_levels || self.equalize).then(|| histogram(img, &luma));
+        let (range, gains) = match &hist {
+            Some(hist) if self.equalize => ((0.0, LUMA_MAX as f32), Some(equalize(hist))),
+            Some(hist) => (levels(hist), None),
+            None => ((0.0, LUMA_MAX as f32), None),
+        };
+        let curves = self.balance().map(|gain| self.curve(gain, range));
+        let saturation = self.saturation;
+
+        img.as_mut().par_chunks_exact_mut(4).for_each(|pixel| {
+            let gain = gains.as_ref().map_or(1.0, |g| {
+                g[luma.index(pixel[0], pixel[1], pixel[2]) * HIST_BINS / (LUMA_MAX + 1)]
+            });
+            let mut c = [0, 1, 2].map(|i| {
+                let v = (pixel[i] as f32 * gain).round().min(65535.0) as usize;
+                curves[i][v]
+            });
+
+            if saturation != 1.0 {
+                let gray = 0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2];
+                c = c.map(|v| (gray + (v - gray) * saturation).clamp(0.0, 1.0));
+            }
+
+            for i in 0..3 {
+                pixel[i] = (c[i] * 65535.0).round() as u16;
+            }
+        });
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("exposure", Value::F32(self.exposure)),
+            ("contrast", Value::F32(self.contrast)),
+            ("gamma", Value::F32(self.gamma)),
+            ("saturation", Value::F32(self.saturation)),
+            ("temperature", Value::F32(self.temperature)),
+            ("tint", Value::F32(self.tint)),
+            ("auto_levels", Value::Bool(self.auto_levels)),
+            ("equalize", Value::Bool(self.equalize)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("exposure", Param::F32(&mut self.exposure, -4.0..=4.0)),
+            ("contrast", Param::F32(&mut self.contrast, -1.0..=1.0)),
+            ("gamma", Param::F32(&mut self.gamma, 0.2..=5.0)),
+            ("saturation", Param::F32(&mut self.saturation, 0.0..=2.0)),
+            ("temperature", Param::F32(&mut self.temperature, -1.0..=1.0)),
+            ("tint", Param::F32(&mut self.tint, -1.0..=1.0)),
+            ("auto_levels", Param::Bool(&mut self.auto_levels)),
+            ("equalize", Param::Bool(&mut self.equalize)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_curve_is_identity() {
+        let curve = Tone::default().curve(1.0, (0.0, 65535.0));
+        for v in [0u16, 1000, 32768, 65535] {
+            let out = (curve[v as usize] * 65535.0).round() as u16;
+            assert!(out.abs_diff(v) <= 1, "{v} -> {out}");
+        }
+    }
+
+    #[test]
+    fn levels_stretch_dark_image() {
+        // Everything between 10% and 30% brightness
+        let img = MilkBuffer::from_fn(100, 1, |x, _| {
+            let v = 6554 + x as u16 * 131;
+            image::Rgba([v, v, v, 65535])
+        });
+        let hist = histogram(&img, &LumaLut::new(crate::color::Luma::Average));
+
+        let (lo, hi) = levels(&hist);
+        assert!((6000.0..7000.0).contains(&lo), "low end {lo}");
+        assert!((19000.0..20000.0).contains(&hi), "high end {hi}");
+
+        let gains = equalize(&hist);
+        let mid = 13000 * HIST_BINS / (LUMA_MAX + 1);
+        assert!(gains[mid] > 2.0, "middle of a dark image gets brighter");
+    }
+}
@@ -1,4 +1,13 @@
-use crate::filt::MilkImage;
+use crate::band::{Band, BandTable};
+use crate::blend::BlendMode;
+use crate::color::Luma;
+use crate::curve::Curve;
+use crate::dither::{Dither, Kernel};
+use crate::filt::{AlphaMode, MilkConfig, MilkImage};
+use crate::mask::{KeyKind, Mask};
+use crate::nearest::Mapping;
+use crate::palette::{MAX_COLORS, MIN_COLORS, Palette};
+use crate::pipeline::{EFFECTS, Param, Pipeline, effect_by_name};
 use egui::{Color32, RichText};
 use std::future::Future;
 use std::sync::mpsc::{Receiver, Sender, channel};
@@ -36,9 +45,20 @@ pub struct MilkApp {
     show_about: bool,
     show_config: bool,
     file_ch: (Sender<FileDN>, Receiver<FileDN>),
+    ref_ch: (Sender<FileDN>, Receiver<FileDN>),
     file: FileDN,
     img: MilkImage,
     texture: Option<egui::TextureHandle>,
+    band_draft: Vec<Band>,
+    conf_error: Option<String>,
+    derive_count: usize,
+    pipeline_text: String,
+    mask_ch: (Sender<FileDN>, Receiver<FileDN>),
+    mask_tool: MaskTool,
+    brush_size: f32,
+    // Image space points of the stroke being drawn
+    stroke: Vec<(f32, f32)>,
+    mask_texture: Option<egui::TextureHandle>,
 }
 
 impl Default for MilkApp {
@@ -47,9 +67,19 @@ impl Default for MilkApp {
             show_about: false,
             show_config: false,
             file_ch: channel(),
+            ref_ch: channel(),
             file: FileDN::default(),
             img: MilkImage::new(),
             texture: None,
+            band_draft: Vec::new(),
+            conf_error: None,
+            derive_count: 3,
+            pipeline_text: String::new(),
+            mask_ch: channel(),
+            mask_tool: MaskTool::Off,
+            brush_size: 24.0,
+            stroke: Vec::new(),
+            mask_texture: None,
         }
     }
 }
@@ -76,24 +106,17 @@ impl eframe::App for MilkApp {
             if self.file.valid {
                 puffin::profile_scope!("s_load_and_process_image");
                 self.img.open(&self.file.data);
-                self.img.process();
+                self.mask_texture = None;
+                self.reprocess(ctx);
+            }
+        }
 
-                if let Some(img) = &self.img.processed {
-                    puffin::profile_scope!("s_load_texture");
-                    let color_image = egui::ColorImage::from_rgb(
-                        [img.width() as usize, img.height() as usize],
-                        img.as_raw().as_slice(),
-                    );
+        if let Ok(file) = self.mask_ch.1.try_recv() {
+            self.load_mask(&file, ctx);
+        }
 
-                    self.texture = Some(ctx.load_texture(
-                        "image",
-                        color_image,
-                        egui::TextureOptions::default(),
-                    ));
-                } else {
-                    self.texture = None;
-                }
-            }
+        if let Ok(file) = self.ref_ch.1.try_recv() {
+            self.derive_palette(&file, ctx);
         }
 
         self.window_about(ctx);
@@ -143,18 +166,32 @@ impl eframe::App for MilkApp {
             if !self.file.exists {
                 ui.label("Load file by using \"Load\" button!");
             } else if !self.file.valid {
-                ui.label(RichText::new("(png | jpg | jpeg) Only supported!").color(Color32::RED));
+                ui.label(
+                    RichText::new("(png | jpg | jpeg | tiff) Only supported!").color(Color32::RED),
+                );
             } else {
                 ui.horizontal(|ui| {
                     ui.label("editing: ");
                     ui.label(RichText::new(&self.file.name).color(Color32::GREEN));
                 });
+                self.mask_toolbar(ui);
             }
 
-            if let Some(tex) = &self.texture {
+            if let Some(tex) = self.texture.clone() {
                 puffin::profile_scope!("s_draw_img");
                 let max_size = ui.available_size() * 0.9;
-                ui.add(egui::Image::new(tex).max_size(max_size));
+                let sense = if self.mask_tool == MaskTool::Off {
+                    egui::Sense::hover()
+                } else {
+                    egui::Sense::drag()
+                };
+                let resp = ui.add(egui::Image::new(&tex).max_size(max_size).sense(sense));
+
+                if let Some(mask) = &self.mask_texture {
+                    let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+                    ui.painter().image(mask.id(), resp.rect, uv, Color32::WHITE);
+                }
+                self.paint_mask(ui, &resp);
             }
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
@@ -221,7 +258,229 @@ impl MilkApp {
             });
     }
 
+    /// Runs the filter again and uploads the result
+    fn reprocess(&mut self, ctx: &egui::Context) {
+        if !self.file.valid {
+            return;
+        }
+        self.img.process();
+        self.upload(ctx);
+    }
+
+    fn upload(&mut self, ctx: &egui::Context) {
+        self.texture = self.img.processed_rgba8().map(|img| {
+            puffin::profile_scope!("s_load_texture");
+            let color_image = egui::ColorImage::from_rgba_unmultiplied(
+                [img.width() as usize, img.height() as usize],
+                img.as_raw().as_slice(),
+            );
+
+            ctx.load_texture("image", color_image, egui::TextureOptions::default())
+        });
+    }
+
+    /// Reapplies the mask and refreshes both textures
+    fn remask(&mut self, ctx: &egui::Context) {
+        self.img.composite();
+        self.upload(ctx);
+        self.upload_mask(ctx);
+    }
+
+    // Tint over the parts of the image the effects do not reach
+    fn upload_mask(&mut self, ctx: &egui::Context) {
+        puffin::profile_function!();
+
+        self.mask_texture = self.img.mask.as_ref().map(|mask| {
+            let img = mask.as_image();
+            let rgba: Vec<u8> = img
+                .as_raw()
+                .iter()
+                .flat_map(|&m| [255, 0, 64, (255 - m) / 2])
+                .collect();
+            let color_image = egui::ColorImage::from_rgba_unmultiplied(
+                [img.width() as usize, img.height() as usize],
+                &rgba,
+            );
+
+            ctx.load_texture("mask", color_image, egui::TextureOptions::default())
+        });
+    }
+
+    fn mask_toolbar(&mut self, ui: &mut egui::Ui) {
+        let mut remask = false;
+
+        ui.horizontal_wrapped(|ui| {
+            enum_combo(
+                ui,
+                "Mask",
+                &mut self.mask_tool,
+                &MaskTool::ALL,
+                MaskTool::name,
+            );
+            if matches!(self.mask_tool, MaskTool::Brush | MaskTool::Eraser) {
+                ui.add(egui::Slider::new(&mut self.brush_size, 1.0..=256.0).text("Brush"));
+            }
+            remask |= committed(&ui.add(
+                egui::Slider::new(&mut self.img.get_config().feather, 0..=128).text("Feather"),
+            ));
+
+            let size = self.img.dimensions();
+            if ui.button("Fill").clicked() {
+                self.img.mask = size.map(|(w, h)| Mask::new(w, h, 255));
+                remask = true;
+            }
+            if ui.button("Clear").clicked() {
+                self.img.mask = size.map(|(w, h)| Mask::new(w, h, 0));
+                remask = true;
+            }
+            if let Some(mask) = &mut self.img.mask {
+                if ui.button("Invert").clicked() {
+                    mask.invert();
+                    remask = true;
+                }
+                if ui.button("Remove").clicked() {
+                    self.img.mask = None;
+                    remask = true;
+                }
+            }
+
+            if ui.button("Load mask").clicked() {
+                pick_file(self.mask_ch.0.clone(), ui.ctx().clone());
+            }
+            if let Some(mask) = &self.img.mask
+                && ui.button("Save mask").clicked()
+            {
+                match mask.to_png() {
+                    Ok(png) => save_file(sidecar_name(&self.file.name), png),
+                    Err(e) => self.conf_error = Some(e.to_string()),
+                }
+            }
+        });
+
+        if remask {
+            self.remask(ui.ctx());
+        }
+    }
+
+    fn paint_mask(&mut self, ui: &egui::Ui, resp: &egui::Response) {
+        let (Some((w, h)), Some(pointer)) = (self.img.dimensions(), resp.interact_pointer_pos())
+        else {
+            return;
+        };
+        let rect = resp.rect;
+        let to_image = |p: egui::Pos2| {
+            (
+                (p.x - rect.min.x) / rect.width() * w as f32,
+                (p.y - rect.min.y) / rect.height() * h as f32,
+            )
+        };
+        let to_screen = |(x, y): (f32, f32)| {
+            egui::pos2(
+                rect.min.x + x / w as f32 * rect.width(),
+                rect.min.y + y / h as f32 * rect.height(),
+            )
+        };
+        let pos = to_image(pointer);
+
+        if resp.drag_started() {
+            self.stroke.clear();
+            if self.img.mask.is_none() {
+                // Erasing starts from full effect, everything else from none
+                let value = if self.mask_tool == MaskTool::Eraser {
+                    255
+                } else {
+                    0
+                };
+                self.img.mask = Some(Mask::new(w, h, value));
+            }
+        }
+
+        if resp.dragged() {
+            let last = self.stroke.last().copied().unwrap_or(pos);
+            let Some(mask) = &mut self.img.mask else {
+                return;
+            };
+            match self.mask_tool {
+                MaskTool::Brush => mask.stroke(last, pos, self.brush_size / 2.0, 255),
+                MaskTool::Eraser => mask.stroke(last, pos, self.brush_size / 2.0, 0),
+                MaskTool::Rectangle | MaskTool::Lasso | MaskTool::Off => {}
+            }
+            if self.stroke.last() != Some(&pos) {
+                self.stroke.push(pos);
+            }
+
+            let stroke = egui::Stroke::new(1.5, Color32::YELLOW);
+            match self.mask_tool {
+                MaskTool::Brush | MaskTool::Eraser => self.upload_mask(ui.ctx()),
+                MaskTool::Rectangle => {
+                    let start = to_screen(self.stroke[0]);
+                    ui.painter().rect_stroke(
+                        egui::Rect::from_two_pos(start, pointer),
+                        0.0,
+                        stroke,
+                        egui::StrokeKind::Middle,
+                    );
+                }
+                MaskTool::Lasso => {
+                    let points = self.stroke.iter().map(|&p| to_screen(p)).collect();
+                    ui.painter().add(egui::Shape::line(points, stroke));
+                }
+                MaskTool::Off => {}
+            }
+        }
+
+        if resp.drag_stopped() {
+            let start = self.stroke.first().copied().unwrap_or(pos);
+            if let Some(mask) = &mut self.img.mask {
+                match self.mask_tool {
+                    MaskTool::Rectangle => mask.fill_rect(start, pos, 255),
+                    MaskTool::Lasso => mask.fill_polygon(&self.stroke, 255),
+                    MaskTool::Brush | MaskTool::Eraser | MaskTool::Off => {}
+                }
+            }
+            self.stroke.clear();
+            self.remask(ui.ctx());
+        }
+    }
+
+    fn load_mask(&mut self, file: &FileDN, ctx: &egui::Context) {
+        let Some((w, h)) = self.img.dimensions() else {
+            return;
+        };
+        match Mask::from_png(&file.data, w, h) {
+            Ok(mask) => {
+                self.img.mask = Some(mask);
+                self.conf_error = None;
+                self.remask(ctx);
+            }
+            Err(e) => self.conf_error = Some(format!("{}: {e}", file.name)),
+        }
+    }
+
+    fn derive_palette(&mut self, file: &FileDN, ctx: &egui::Context) {
+        puffin::profile_function!();
+
+        let palette = image::load_from_memory(&file.data)
+            .map_err(|e| e.to_string())
+            .and_then(|img| {
+                Palette::from_image(&img.into_rgba8(), self.derive_count).map_err(|e| e.to_string())
+            });
+
+        match palette {
+            Ok(palette) => {
+                let conf = self.img.get_config();
+                conf.replace_palette(palette);
+                self.band_draft = conf.bands().bands().to_vec();
+                self.conf_error = None;
+                self.reprocess(ctx);
+            }
+            Err(e) => self.conf_error = Some(format!("{}: {e}", file.name)),
+        }
+    }
+
     fn window_config(&mut self, ctx: &egui::Context) {
+        let mut reprocess = false;
+        let mut remask = false;
         egui::Window::new("Config")
             .open(&mut self.show_config)
             .vscroll(true)
@@ -230,47 +489,572 @@ impl MilkApp {
             .default_size(egui::vec2(200.0, 200.0))
             .show(ctx, |ui| {
                 let conf = self.img.get_config();
+                if self.band_draft.is_empty() {
+                    self.band_draft = conf.bands().bands().to_vec();
+                }
+
+                if let Some(err) = &self.conf_error {
+                    ui.label(RichText::new(err).color(Color32::RED));
+                }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Derive palette from image").clicked() {
+                        pick_file(self.ref_ch.0.clone(), ui.ctx().clone());
+                    }
+                    ui.add(
+                        egui::DragValue::new(&mut self.derive_count)
+                            .range(MIN_COLORS..=MAX_COLORS)
+                            .suffix(" colors"),
+                    );
+                });
 
-                if ui.checkbox(&mut conf.alt, "Alternative pallete").changed()
-                    || ui.checkbox(&mut conf.pointism, "Pointillism").changed()
-                    || ui.checkbox(&mut conf.enabled, "Milk Enabled").changed()
-                    || ui.checkbox(&mut conf.quant, "Quant").changed()
-                    || ui.checkbox(&mut conf.block, "Blocks").changed()
-                    || ui
-                        .add(
-                            egui::Slider::new(&mut conf.comp, 0..=100)
-                                .text("(Slow) Compression")
-                                .suffix(" %"),
-                        )
-                        .lost_focus()
-                    || ui
-                        .add(
-                            egui::Slider::new(&mut conf.block_size, 0..=64)
-                                .text("Block size(0 = auto)"),
-                        )
-                        .lost_focus()
+                if palette_editor(ui, conf, &mut self.band_draft, &mut self.conf_error)
+                    || band_editor(ui, conf, &mut self.band_draft, &mut self.conf_error)
+                    || mapping_options(ui, conf)
+                    || dither_options(ui, conf)
+                    || alpha_options(ui, conf)
+                    || pipeline_editor(
+                        ui,
+                        self.img.get_pipeline(),
+                        &mut self.pipeline_text,
+                        &mut self.conf_error,
+                    )
                     || ui.button("Reprocess image").clicked()
                 {
-                    if self.file.valid {
-                        self.img.process();
-                        if let Some(img) = &self.img.processed {
-                            puffin::profile_scope!("s_load_texture");
-                            let color_image = egui::ColorImage::from_rgb(
-                                [img.width() as usize, img.height() as usize],
-                                img.as_raw().as_slice(),
-                            );
-
-                            self.texture = Some(ctx.load_texture(
-                                "image",
-                                color_image,
-                                egui::TextureOptions::default(),
-                            ));
-                        }
-                    }
+                    reprocess = true;
                 }
 
+                let conf = self.img.get_config();
+                remask = blend_options(ui, conf) | key_options(ui, conf);
+
                 //TODO: Add other config options
             });
+
+        if reprocess {
+            self.reprocess(ctx);
+        } else if remask {
+            self.remask(ctx);
+        }
+    }
+}
+
+/// Slider or drag value finished editing
+fn committed(resp: &egui::Response) -> bool {
+    resp.drag_stopped() || resp.lost_focus()
+}
+
+fn enum_combo<T: Copy + PartialEq>(
+    ui: &mut egui::Ui,
+    label: &str,
+    value: &mut T,
+    all: &[T],
+    name: fn(T) -> &'static str,
+) -> bool {
+    let before = *value;
+    egui::ComboBox::from_label(label)
+        .selected_text(name(*value))
+        .show_ui(ui, |ui| {
+            for &v in all {
+                ui.selectable_value(value, v, name(v));
+            }
+        });
+    *value != before
+}
+
+/// How dragging over the preview edits the mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MaskTool {
+    Off,
+    Brush,
+    Eraser,
+    Rectangle,
+    Lasso,
+}
+
+impl MaskTool {
+    const ALL: [Self; 5] = [
+        Self::Off,
+        Self::Brush,
+        Self::Eraser,
+        Self::Rectangle,
+        Self::Lasso,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Brush => "Brush",
+            Self::Eraser => "Eraser",
+            Self::Rectangle => "Rectangle",
+            Self::Lasso => "Lasso",
+        }
+    }
+}
+
+fn pipeline_editor(
+    ui: &mut egui::Ui,
+    pipeline: &mut Pipeline,
+    text: &mut String,
+    error: &mut Option<String>,
+) -> bool {
+    let mut changed = false;
+
+    ui.collapsing("Pipeline", |ui| {
+        let count = pipeline.stages().len();
+        let mut action = None;
+
+        for (i, stage) in pipeline.stages_mut().iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                changed |= ui
+                    .checkbox(&mut stage.enabled, stage.effect.name())
+                    .changed();
+                if ui.add_enabled(i > 0, egui::Button::new("⏶")).clicked() {
+                    action = Some((i, StageAction::Up));
+                }
+                if ui
+                    .add_enabled(i + 1 < count, egui::Button::new("⏷"))
+                    .clicked()
+                {
+                    action = Some((i, StageAction::Down));
+                }
+                if ui.button("✖").clicked() {
+                    action = Some((i, StageAction::Remove));
+                }
+            });
+
+            ui.indent(i, |ui| {
+                for (name, param) in stage.effect.params() {
+                    let name = name.replace('_', " ");
+                    changed |= match param {
+                        Param::Bool(v) => ui.checkbox(v, name).changed(),
+                        Param::U32(v, range) => {
+                            committed(&ui.add(egui::Slider::new(v, range).text(name)))
+                        }
+                        Param::F32(v, range) => {
+                            committed(&ui.add(egui::Slider::new(v, range).text(name)))
+                        }
+                        Param::Choice(v, labels) => {
+                            let before = *v;
+                            egui::ComboBox::from_label(name)
+                                .selected_text(labels.get(*v as usize).copied().unwrap_or("?"))
+                                .show_ui(ui, |ui| {
+                                    for (i, label) in (0..).zip(labels) {
+                                        ui.selectable_value(v, i, *label);
+                                    }
+                                });
+                            *v != before
+                        }
+                    };
+                }
+            });
+        }
+
+        match action {
+            Some((i, StageAction::Up)) => pipeline.stages_mut().swap(i - 1, i),
+            Some((i, StageAction::Down)) => pipeline.stages_mut().swap(i, i + 1),
+            Some((i, StageAction::Remove)) => drop(pipeline.remove(i)),
+            None => {}
+        }
+        changed |= action.is_some();
+
+        egui::ComboBox::from_label("Add effect")
+            .selected_text("…")
+            .show_ui(ui, |ui| {
+                for name in EFFECTS {
+                    if ui.selectable_label(false, name).clicked()
+                        && let Some(effect) = effect_by_name(name)
+                    {
+                        pipeline.push(effect);
+                        changed = true;
+                    }
+                }
+            });
+
+        ui.add(
+            egui::TextEdit::multiline(text)
+                .code_editor()
+                .desired_rows(3),
+        );
+        ui.horizontal(|ui| {
+            if ui.button("Save to text").clicked() {
+                *text = pipeline.serialize();
+            }
+            if ui.button("Load from text").clicked() {
+                match Pipeline::deserialize(text) {
+                    Ok(loaded) => {
+                        *pipeline = loaded;
+                        *error = None;
+                        changed = true;
+                    }
+                    Err(e) => *error = Some(e.to_string()),
+                }
+            }
+        });
+    });
+
+    changed
+}
+
+#[derive(Clone, Copy)]
+enum StageAction {
+    Up,
+    Down,
+    Remove,
+}
+
+fn blend_options(ui: &mut egui::Ui, conf: &mut MilkConfig) -> bool {
+    let mut changed = enum_combo(
+        ui,
+        "Blend",
+        &mut conf.blend,
+        &BlendMode::ALL,
+        BlendMode::name,
+    );
+    let mut percent = conf.opacity * 100.0;
+    if committed(
+        &ui.add(
+            egui::Slider::new(&mut percent, 0.0..=100.0)
+                .text("Effect strength")
+                .suffix(" %"),
+        ),
+    ) {
+        conf.opacity = percent / 100.0;
+        changed = true;
+    }
+    changed
+}
+
+fn key_options(ui: &mut egui::Ui, conf: &mut MilkConfig) -> bool {
+    let key = &mut conf.key;
+    let mut changed = false;
+
+    ui.collapsing("Procedural mask", |ui| {
+        changed |= enum_combo(ui, "Kind", &mut key.kind, &KeyKind::ALL, KeyKind::name);
+        if key.kind == KeyKind::Off {
+            return;
+        }
+        changed |= ui.checkbox(&mut key.invert, "Invert").changed();
+
+        let mut slider = |ui: &mut egui::Ui, v: &mut f32, range, text| {
+            changed |= committed(&ui.add(egui::Slider::new(v, range).text(text)));
+        };
+        match key.kind {
+            KeyKind::Luma => {
+                slider(ui, &mut key.lo, 0.0..=1.0, "From");
+                slider(ui, &mut key.hi, 0.0..=1.0, "To");
+                slider(ui, &mut key.softness, 0.0..=1.0, "Softness");
+            }
+            KeyKind::Color => {
+                slider(ui, &mut key.hue, 0.0..=360.0, "Hue");
+                slider(ui, &mut key.tolerance, 0.0..=180.0, "Tolerance");
+                slider(ui, &mut key.min_sat, 0.0..=1.0, "Min saturation");
+                slider(ui, &mut key.softness, 0.0..=1.0, "Softness");
+            }
+            KeyKind::Linear | KeyKind::Radial => {
+                let (a, b) = if key.kind == KeyKind::Linear {
+                    ("Start", "End")
+                } else {
+                    ("Center", "Edge")
+                };
+                for (point, name) in [(&mut key.from, a), (&mut key.to, b)] {
+                    ui.horizontal(|ui| {
+                        ui.label(name);
+                        slider(ui, &mut point[0], 0.0..=1.0, "x");
+                        slider(ui, &mut point[1], 0.0..=1.0, "y");
+                    });
+                }
+            }
+            KeyKind::Off => {}
+        }
+    });
+
+    changed
+}
+
+/// Curve plot with draggable points, click adds a point and right click removes it.
+/// `marks` are band ends drawn as horizontal lines.
+fn curve_editor(ui: &mut egui::Ui, curve: &mut Curve, marks: &[f32]) -> bool {
+    const GRAB: f32 = 8.0;
+
+    let (resp, painter) =
+        ui.allocate_painter(egui::vec2(200.0, 200.0), egui::Sense::click_and_drag());
+    let rect = resp.rect;
+    let to_screen = |(x, y): (f32, f32)| {
+        egui::pos2(
+            rect.left() + x * rect.width(),
+            rect.bottom() - y * rect.height(),
+        )
+    };
+    let to_curve = |p: egui::Pos2| {
+        (
+            (p.x - rect.left()) / rect.width(),
+            (rect.bottom() - p.y) / rect.height(),
+        )
+    };
+    let hovered = |pos: egui::Pos2, curve: &Curve| {
+        curve
+            .points()
+            .iter()
+            .position(|&p| to_screen(p).distance(pos) < GRAB)
+    };
+
+    let dragging = resp.id.with("dragging");
+    let mut changed = false;
+
+    if let Some(pos) = resp.interact_pointer_pos() {
+        if resp.drag_started() {
+            ui.data_mut(|d| d.insert_temp(dragging, hovered(pos, curve)));
+        }
+        if resp.dragged()
+            && let Some(i) = ui.data(|d| d.get_temp::<Option<usize>>(dragging)).flatten()
+        {
+            let (x, y) = to_curve(pos);
+            curve.set(i, x, y);
+        }
+        changed |= resp.drag_stopped();
+
+        if resp.clicked() && hovered(pos, curve).is_none() {
+            let (x, y) = to_curve(pos);
+            changed |= curve.insert(x, y).is_some();
+        }
+        if resp.secondary_clicked()
+            && let Some(i) = hovered(pos, curve)
+        {
+            curve.remove(i);
+            changed = true;
+        }
+    }
+
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 0.0, visuals.extreme_bg_color);
+    for &m in marks {
+        painter.hline(
+            rect.x_range(),
+            to_screen((0.0, m)).y,
+            egui::Stroke::new(1.0, visuals.weak_text_color()),
+        );
+    }
+    let line = curve
+        .table(64)
+        .iter()
+        .enumerate()
+        .map(|(i, &y)| to_screen((i as f32 / 63.0, y)))
+        .collect();
+    painter.add(egui::Shape::line(
+        line,
+        egui::Stroke::new(2.0, Color32::YELLOW),
+    ));
+    for &p in curve.points() {
+        painter.circle_filled(to_screen(p), 4.0, Color32::WHITE);
+    }
+
+    changed
+}
+
+fn mapping_options(ui: &mut egui::Ui, conf: &mut MilkConfig) -> bool {
+    let mut changed = enum_combo(
+        ui,
+        "Mapping",
+        &mut conf.mapping,
+        &Mapping::ALL,
+        Mapping::name,
+    );
+
+    if conf.mapping == Mapping::Bands {
+        changed |= enum_combo(ui, "Luminance", &mut conf.luma, &Luma::ALL, Luma::name);
+
+        let marks: Vec<f32> = conf
+            .bands()
+            .bands()
+            .iter()
+            .map(|b| b.upto as f32 / 255.0)
+            .collect();
+        ui.collapsing("Tone curve", |ui| {
+            changed |= curve_editor(ui, &mut conf.curve, &marks);
+            if ui.button("Reset curve").clicked() {
+                conf.curve = Curve::default();
+                changed = true;
+            }
+        });
+    } else {
+        changed |= committed(
+            &ui.add(egui::Slider::new(&mut conf.hue_weight, 0.0..=8.0).text("Hue weight")),
+        );
+    }
+
+    changed
+}
+
+fn dither_options(ui: &mut egui::Ui, conf: &mut MilkConfig) -> bool {
+    let mut changed = enum_combo(ui, "Dither", &mut conf.dither, &Dither::ALL, Dither::name);
+
+    if matches!(conf.dither, Dither::Random | Dither::BlueNoise) {
+        ui.horizontal(|ui| {
+            changed |=
+                committed(&ui.add(egui::Slider::new(&mut conf.density, 0.0..=3.0).text("Density")));
+            if ui.button("Reroll").clicked() {
+                conf.seed = crate::smix64::random();
+                changed = true;
+            }
+        });
+    }
+    if conf.dither == Dither::BlueNoise {
+        changed |= enum_combo(
+            ui,
+            "Noise size",
+            &mut conf.noise_size,
+            &[16, 32, 64],
+            |s| match s {
+                16 => "16x16",
+                32 => "32x32",
+                _ => "64x64",
+            },
+        );
+    }
+    if conf.dither == Dither::Diffusion {
+        changed |= enum_combo(ui, "Kernel", &mut conf.kernel, &Kernel::ALL, Kernel::name);
+        changed |= committed(
+            &ui.add(egui::Slider::new(&mut conf.strength, 0.0..=1.0).text("Diffusion strength")),
+        );
+    }
+
+    changed
+}
+
+fn alpha_options(ui: &mut egui::Ui, conf: &mut MilkConfig) -> bool {
+    let mut changed = enum_combo(
+        ui,
+        "Alpha",
+        &mut conf.alpha,
+        &AlphaMode::ALL,
+        AlphaMode::name,
+    );
+    if conf.alpha == AlphaMode::Threshold {
+        changed |= committed(
+            &ui.add(egui::Slider::new(&mut conf.alpha_threshold, 0..=255).text("Alpha threshold")),
+        );
+    }
+    changed
+}
+
+fn palette_editor(
+    ui: &mut egui::Ui,
+    conf: &mut MilkConfig,
+    band_draft: &mut Vec<Band>,
+    error: &mut Option<String>,
+) -> bool {
+    let mut builtin = None;
+    ui.horizontal(|ui| {
+        ui.label("Palette:");
+        if ui.button("Milk").clicked() {
+            builtin = Some(false);
+        }
+        if ui.button("Alternative").clicked() {
+            builtin = Some(true);
+        }
+    });
+    if let Some(alt) = builtin {
+        conf.set_builtin(alt);
+        *band_draft = conf.bands().bands().to_vec();
+        *error = None;
+        return true;
+    }
+
+    let mut palette = conf.palette().clone();
+    let mut changed = false;
+    let mut remove = None;
+    ui.horizontal_wrapped(|ui| {
+        for (i, color) in palette.colors_mut().iter_mut().enumerate() {
+            let mut rgb = [color.0, color.1, color.2];
+            let resp = ui.color_edit_button_srgb(&mut rgb);
+            if resp.changed() {
+                *color = rgb.into();
+                changed = true;
+            }
+            if resp.secondary_clicked() {
+                remove = Some(i);
+            }
+        }
+        if ui.button("+").clicked() {
+            let last = palette.colors().last().copied().unwrap_or_default();
+            changed |= palette.push(last).is_ok();
+        }
+    });
+    if let Some(i) = remove {
+        changed |= palette.remove(i).is_ok();
+    }
+    ui.label(RichText::new("Right click a color to remove it").small());
+
+    if !changed {
+        return false;
+    }
+    conf.set_palette(palette);
+    *band_draft = conf.bands().bands().to_vec();
+    *error = None;
+    true
+}
+
+fn band_editor(
+    ui: &mut egui::Ui,
+    conf: &mut MilkConfig,
+    draft: &mut Vec<Band>,
+    error: &mut Option<String>,
+) -> bool {
+    let slots = conf.palette().colors().len() - 1;
+    let mut commit = false;
+
+    egui::CollapsingHeader::new("Bands").show(ui, |ui| {
+        egui::Grid::new("bands").striped(true).show(ui, |ui| {
+            ui.label("Up to");
+            ui.label("Primary");
+            ui.label("Secondary");
+            ui.label("Mix");
+            ui.end_row();
+
+            for band in draft.iter_mut() {
+                let resp = [
+                    ui.add(egui::DragValue::new(&mut band.upto)),
+                    ui.add(egui::DragValue::new(&mut band.primary).range(0..=slots)),
+                    ui.add(egui::DragValue::new(&mut band.secondary).range(0..=slots)),
+                    ui.add(egui::Slider::new(&mut band.mix, 0.0..=1.0)),
+                ];
+                commit |= resp.iter().any(committed);
+                ui.end_row();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("+").clicked() {
+                let last = draft.last().copied();
+                if let Some(mut band) = last {
+                    band.upto = band.upto.saturating_sub(1);
+                    draft.insert(draft.len() - 1, band);
+                }
+                commit = true;
+            }
+            if ui.button("-").clicked() && draft.len() > 1 {
+                draft.remove(draft.len() - 2);
+                commit = true;
+            }
+            if ui.button("Reset").clicked() {
+                *draft = conf.default_bands().bands().to_vec();
+                commit = true;
+            }
+        });
+    });
+
+    if !commit {
+        return false;
+    }
+    match BandTable::new(draft.clone(), slots + 1).and_then(|t| conf.set_bands(t)) {
+        Ok(()) => {
+            *error = None;
+            true
+        }
+        Err(e) => {
+            *error = Some(e.to_string());
+            false
+        }
     }
 }
 
@@ -292,6 +1076,25 @@ fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     });
 }
 
+fn pick_file(sender: Sender<FileDN>, ctx: egui::Context) {
+    let task = rfd::AsyncFileDialog::new().pick_file();
+
+    execute(async move {
+        let file = task.await;
+        if let Some(file) = file {
+            let data = file.read().await;
+            let name = file.file_name();
+            let valid = name.ends_with(".png")
+                || name.ends_with(".jpg")
+                || name.ends_with(".jpeg")
+                || name.ends_with(".tif")
+                || name.ends_with(".tiff");
+            let _ = sender.send(FileDN::new(name, data, valid));
+            ctx.request_repaint();
+        }
+    });
+}
+
 fn load_save_file(app: &MilkApp, ui: &mut egui::Ui) {
     ui.with_layout(
         egui::Layout::top_down_justified(egui::Align::Center),
@@ -300,22 +1103,7 @@ fn load_save_file(app: &MilkApp, ui: &mut egui::Ui) {
                 .button(RichText::new("Load").color(Color32::WHITE))
                 .clicked()
             {
-                let sender = app.file_ch.0.clone();
-                let task = rfd::AsyncFileDialog::new().pick_file();
-
-                let ctx = ui.ctx().clone();
-                execute(async move {
-                    let file = task.await;
-                    if let Some(file) = file {
-                        let data = file.read().await;
-                        let name = file.file_name();
-                        let valid = name.ends_with(".png")
-                            || name.ends_with(".jpg")
-                            || name.ends_with(".jpeg");
-                        let _ = sender.send(FileDN::new(name, data, valid));
-                        ctx.request_repaint();
-                    }
-                });
+                pick_file(app.file_ch.0.clone(), ui.ctx().clone());
             }
 
             if ui
@@ -323,29 +1111,36 @@ fn load_save_file(app: &MilkApp, ui: &mut egui::Ui) {
                 .clicked()
             {
                 let id = crate::smix64::random();
-                let task = rfd::AsyncFileDialog::new()
-                    .set_file_name(format!("filt_{id:16x}.png"))
-                    .save_file();
-
-                if let Some(img) = &app.img.processed {
+                if let Some(img) = app.img.processed_rgba8() {
                     let size = img.width() as usize * img.height() as usize;
                     let mut buf = Vec::with_capacity(size);
 
                     img.write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
                         .unwrap();
-
-                    execute(async move {
-                        let file = task.await;
-                        if let Some(file) = file {
-                            _ = file.write(buf.as_slice()).await;
-                        }
-                    });
+                    save_file(format!("filt_{id:16x}.png"), buf);
                 }
             }
         },
     );
 }
 
+/// Mask file saved next to `name`, `photo.jpg` gets `photo.mask.png`
+fn sidecar_name(name: &str) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    format!("{stem}.mask.png")
+}
+
+fn save_file(name: String, data: Vec<u8>) {
+    let task = rfd::AsyncFileDialog::new().set_file_name(name).save_file();
+
+    execute(async move {
+        let file = task.await;
+        if let Some(file) = file {
+            _ = file.write(data.as_slice()).await;
+        }
+    });
+}
+
 #[cfg(target_arch = "wasm32")]
 fn execute<F: Future<Output = ()> + 'static>(f: F) {
     wasm_bindgen_futures::spawn_local(f);
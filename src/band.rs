@@ -0,0 +1,186 @@
+use std::fmt;
+
+/// One luminance band of the milk LUT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    /// Highest average brightness (0..=255) that still falls into this band
+    pub upto: u8,
+    /// Palette slot used for most pixels of the band
+    pub primary: usize,
+    /// Palette slot mixed in by pointillism
+    pub secondary: usize,
+    /// Probability of picking `secondary` over `primary`
+    pub mix: f32,
+}
+
+impl Band {
+    fn solid(upto: u8, slot: usize) -> Self {
+        Self {
+            upto,
+            primary: slot,
+            secondary: slot,
+            mix: 0.0,
+        }
+    }
+
+    fn mixed(upto: u8, primary: usize, secondary: usize) -> Self {
+        Self {
+            upto,
+            primary,
+            secondary,
+            mix: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandError {
+    Empty,
+    /// Band thresholds must strictly increase
+    Unsorted(usize),
+    /// Last band must reach brightness 255
+    Uncovered(u8),
+    SlotOutOfRange {
+        band: usize,
+        slot: usize,
+    },
+    BadMix {
+        band: usize,
+        mix: f32,
+    },
+}
+
+impl fmt::Display for BandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "band table is empty"),
+            Self::Unsorted(band) => write!(f, "band {band} does not end above the previous one"),
+            Self::Uncovered(upto) => write!(f, "last band ends at {upto} instead of 255"),
+            Self::SlotOutOfRange { band, slot } => {
+                write!(
+                    f,
+                    "band {band} uses palette slot {slot} which does not exist"
+                )
+            }
+            Self::BadMix { band, mix } => {
+                write!(f, "band {band} has mix probability {mix} outside 0..=1")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BandError {}
+
+/// Ordered list of bands covering the whole 0..=255 brightness range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandTable {
+    bands: Vec<Band>,
+}
+
+impl BandTable {
+    /// Creates table for a palette of `palette_len` colors
+    ///
+    /// # Errors
+    /// When bands are unsorted, leave brightness uncovered, reference missing slots
+    /// or have a mix probability outside `0..=1`.
+    pub fn new(bands: Vec<Band>, palette_len: usize) -> Result<Self, BandError> {
+        let table = Self { bands };
+        table.validate(palette_len)?;
+        Ok(table)
+    }
+
+    /// Classic milk layout: solid darkest and lightest bands, interior colors share
+    /// the `mid` brightness range and neighbours are mixed in between.
+    pub fn spread(palette_len: usize, mid: (u8, u8)) -> Self {
+        let last = palette_len - 1;
+        let (mid_lo, mid_hi) = (1, last.saturating_sub(1).max(1));
+        let (thr1, thr2) = mid;
+
+        let mut bands = vec![
+            Band::solid(25, 0),
+            Band::mixed(70, 0, mid_lo),
+            Band::mixed(thr1 - 1, mid_lo, 0),
+        ];
+
+        let count = mid_hi - mid_lo + 1;
+        let span = (thr2 - thr1) as usize;
+        for i in 0..count {
+            let upto = thr1 as usize + span * (i + 1) / count - 1;
+            bands.push(Band::solid(upto as u8, mid_lo + i));
+        }
+
+        bands.push(Band::mixed(229, last, mid_hi));
+        bands.push(Band::solid(255, last));
+
+        Self { bands }
+    }
+
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    /// Checks table against a palette of `palette_len` colors
+    ///
+    /// # Errors
+    /// See [`BandTable::new`].
+    pub fn validate(&self, palette_len: usize) -> Result<(), BandError> {
+        let last = self.bands.last().ok_or(BandError::Empty)?;
+        if last.upto != 255 {
+            return Err(BandError::Uncovered(last.upto));
+        }
+
+        for (i, band) in self.bands.iter().enumerate() {
+            if i > 0 && band.upto <= self.bands[i - 1].upto {
+                return Err(BandError::Unsorted(i));
+            }
+            for slot in [band.primary, band.secondary] {
+                if slot >= palette_len {
+                    return Err(BandError::SlotOutOfRange { band: i, slot });
+                }
+            }
+            if !(0.0..=1.0).contains(&band.mix) {
+                return Err(BandError::BadMix {
+                    band: i,
+                    mix: band.mix,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spread_is_valid() {
+        for len in 2..=16 {
+            let table = BandTable::spread(len, (120, 200));
+            assert_eq!(table.validate(len), Ok(()), "{len} colors");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        let band = Band::solid(255, 0);
+
+        assert_eq!(BandTable::new(vec![], 3), Err(BandError::Empty));
+        assert_eq!(
+            BandTable::new(vec![Band::solid(100, 0)], 3),
+            Err(BandError::Uncovered(100))
+        );
+        assert_eq!(
+            BandTable::new(vec![Band::solid(100, 0), Band::solid(100, 0), band], 3),
+            Err(BandError::Unsorted(1))
+        );
+        assert_eq!(
+            BandTable::new(vec![Band::solid(255, 3)], 3),
+            Err(BandError::SlotOutOfRange { band: 0, slot: 3 })
+        );
+        assert_eq!(
+            BandTable::new(vec![Band { mix: 1.5, ..band }], 3),
+            Err(BandError::BadMix { band: 0, mix: 1.5 })
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use crate::filt::MilkBuffer;
+use rayon::prelude::*;
+
+/// How the processed image is laid over the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    /// Hue and saturation of the effect, lightness of the source
+    Color,
+    /// Lightness of the effect, hue and saturation of the source
+    Luminosity,
+}
+
+impl BlendMode {
+    pub const ALL: [Self; 7] = [
+        Self::Normal,
+        Self::Multiply,
+        Self::Screen,
+        Self::Overlay,
+        Self::SoftLight,
+        Self::Color,
+        Self::Luminosity,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "Normal",
+            Self::Multiply => "Multiply",
+            Self::Screen => "Screen",
+            Self::Overlay => "Overlay",
+            Self::SoftLight => "Soft light",
+            Self::Color => "Color",
+            Self::Luminosity => "Luminosity",
+        }
+    }
+
+    // Blend of effect `top` over source `base`, channels in 0..=1
+    fn apply(self, base: [f32; 3], top: [f32; 3]) -> [f32; 3] {
+        let per_channel = |f: fn(f32, f32) -> f32| [0, 1, 2].map(|c| f(base[c], top[c]));
+
+        match self {
+            Self::Normal => top,
+            Self::Multiply => per_channel(|b, t| b * t),
+            Self::Screen => per_channel(screen),
+            Self::Overlay => per_channel(|b, t| {
+                if b <= 0.5 {
+                    2.0 * b * t
+                } else {
+                    screen(t, 2.0 * b - 1.0)
+                }
+            }),
+            Self::SoftLight => per_channel(|b, t| {
+                if t <= 0.5 {
+                    b - (1.0 - 2.0 * t) * b * (1.0 - b)
+                } else {
+                    let d = if b <= 0.25 {
+                        ((16.0 * b - 12.0) * b + 4.0) * b
+                    } else {
+                        b.sqrt()
+                    };
+                    b + (2.0 * t - 1.0) * (d - b)
+                }
+            }),
+            Self::Color => set_lum(top, lum(base)),
+            Self::Luminosity => set_lum(base, lum(top)),
+        }
+    }
+}
+
+fn screen(b: f32, t: f32) -> f32 {
+    b + t - b * t
+}
+
+fn lum(c: [f32; 3]) -> f32 {
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+// Shifts `c` to lightness `l`, pulling out of gamut channels back towards gray
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3] {
+    let d = l - lum(c);
+    let c = c.map(|v| v + d);
+
+    let l = lum(c);
+    let (lo, hi) = (c[0].min(c[1]).min(c[2]), c[0].max(c[1]).max(c[2]));
+    c.map(|v| {
+        let v = if lo < 0.0 {
+            l + (v - l) * l / (l - lo)
+        } else {
+            v
+        };
+        if hi > 1.0 {
+            l + (v - l) * (1.0 - l) / (hi - l)
+        } else {
+            v
+        }
+    })
+}
+
+/// Blends effect `img` over source `src` and mixes the result with `src` by `opacity`
+pub fn blend(img: &mut MilkBuffer, src: &MilkBuffer, mode: BlendMode, opacity: f32) {
+    puffin::profile_function!();
+
+    if mode == BlendMode::Normal && opacity >= 1.0 {
+        return;
+    }
+    let opacity = opacity.clamp(0.0, 1.0);
+
+    img.as_mut()
+        .par_chunks_exact_mut(4)
+        .zip(src.as_raw().par_chunks_exact(4))
+        .for_each(|(pixel, orig)| {
+            let base = [0, 1, 2].map(|c| orig[c] as f32 / 65535.0);
+            let top = [0, 1, 2].map(|c| pixel[c] as f32 / 65535.0);
+            let out = mode.apply(base, top);
+
+            for c in 0..3 {
+                let v = base[c] + (out[c] - base[c]) * opacity;
+                pixel[c] = (v * 65535.0).round().clamp(0.0, 65535.0) as u16;
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5)
+    }
+
+    #[test]
+    fn modes_against_gray() {
+        let base = [0.5; 3];
+        let top = [0.2, 0.4, 0.8];
+
+        assert!(
+            close(BlendMode::Multiply.apply(base, top), [0.1, 0.2, 0.4]),
+            "multiply"
+        );
+        assert!(
+            close(BlendMode::Screen.apply(base, top), [0.6, 0.7, 0.9]),
+            "screen"
+        );
+        // Overlay with 50% gray backdrop and soft light with 50% gray effect are neutral
+        assert!(close(BlendMode::Overlay.apply(base, top), top), "overlay");
+        assert!(
+            close(BlendMode::SoftLight.apply(top, base), top),
+            "soft light"
+        );
+
+        let color = BlendMode::Color.apply(base, top);
+        assert!((lum(color) - 0.5).abs() < 1e-5, "keeps source lightness");
+        let luminosity = BlendMode::Luminosity.apply(base, top);
+        assert!(
+            (lum(luminosity) - lum(top)).abs() < 1e-5,
+            "takes effect lightness"
+        );
+    }
+
+    #[test]
+    fn opacity_mixes_with_source() {
+        let src = MilkBuffer::from_pixel(2, 1, image::Rgba([0, 0, 0, 65535]));
+        let mut img = MilkBuffer::from_pixel(2, 1, image::Rgba([65535, 32768, 0, 65535]));
+        blend(&mut img, &src, BlendMode::Normal, 0.5);
+        assert_eq!(img.get_pixel(0, 0).0, [32768, 16384, 0, 65535]);
+    }
+}
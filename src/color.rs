@@ -0,0 +1,267 @@
+use std::sync::OnceLock;
+
+/// Largest brightness index of [`LumaLut`]
+pub const LUMA_MAX: usize = u16::MAX as usize;
+
+/// 16 bit sRGB channel value to linear light
+pub fn srgb_to_linear(c: u16) -> f32 {
+    static LIN: OnceLock<Vec<f32>> = OnceLock::new();
+
+    LIN.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|i| {
+                let c = i as f32 / 65535.0;
+                if c <= 0.04045 {
+                    c / 12.92
+                } else {
+                    ((c + 0.055) / 1.055).powf(2.4)
+                }
+            })
+            .collect()
+    })[c as usize]
+}
+
+/// 16 bit sRGB color to Oklab `[L, a, b]`, L is in 0..=1
+pub fn oklab(r: u16, g: u16, b: u16) -> [f32; 3] {
+    linear_oklab(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b))
+}
+
+fn linear_oklab(r: f32, g: f32, b: f32) -> [f32; 3] {
+    let l = (0.412_221_47 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+/// 16 bit sRGB color to CIELAB `[L, a, b]` under D65, L is in 0..=100
+pub fn cielab(r: u16, g: u16, b: u16) -> [f32; 3] {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+    // XYZ relative to the white point
+    let x = (0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b) / 0.950_47;
+    let y = 0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b;
+    let z = (0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b) / 1.088_83;
+
+    let f = |t: f32| {
+        if t > 0.008_856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Linear light to 8 bit sRGB channel value
+pub fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Oklab `[L, a, b]` back to 8 bit sRGB, out of gamut colors are clipped
+pub fn oklab_to_srgb([l, a, b]: [f32; 3]) -> (u8, u8, u8) {
+    let l_ = l + 0.396_337_78 * a + 0.215_803_76 * b;
+    let m_ = l - 0.105_561_346 * a - 0.063_854_17 * b;
+    let s_ = l - 0.089_484_18 * a - 1.291_485_5 * b;
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+
+    (
+        linear_to_srgb(4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s),
+        linear_to_srgb(-1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s),
+        linear_to_srgb(-0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s),
+    )
+}
+
+/// How pixel brightness is measured when picking a band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Luma {
+    /// Plain `(r + g + b) / 3`
+    Average,
+    Rec601,
+    Rec709,
+    /// Perceptual lightness of Oklab
+    OkLab,
+}
+
+impl Luma {
+    pub const ALL: [Self; 4] = [Self::Average, Self::Rec601, Self::Rec709, Self::OkLab];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Average => "Average",
+            Self::Rec601 => "Rec.601",
+            Self::Rec709 => "Rec.709",
+            Self::OkLab => "OKLab L",
+        }
+    }
+}
+
+// Grid steps per channel of the Oklab lightness table
+const OKLAB_STEPS: usize = 64;
+
+// Grid position of every 16 bit channel value, the grid is even in the cube root
+// of linear light so lightness is close to linear inside each cell
+fn oklab_coord(c: u16) -> f32 {
+    static COORD: OnceLock<Vec<f32>> = OnceLock::new();
+
+    COORD.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|c| srgb_to_linear(c).cbrt() * OKLAB_STEPS as f32)
+            .collect()
+    })[c as usize]
+}
+
+// Oklab L at every grid point, blue fastest, built once
+fn oklab_lightness() -> &'static [f32] {
+    static TABLE: OnceLock<Vec<f32>> = OnceLock::new();
+
+    TABLE.get_or_init(|| {
+        let n = OKLAB_STEPS + 1;
+        let level = |i: usize| (i as f32 / OKLAB_STEPS as f32).powi(3);
+        (0..n * n * n)
+            .map(|i| linear_oklab(level(i / (n * n)), level(i / n % n), level(i % n))[0])
+            .collect()
+    })
+}
+
+/// Maps 16 bit pixels to a brightness index in `0..=LUMA_MAX`.
+pub struct LumaLut {
+    mode: Luma,
+    // Channel weights, 16 bit fixed point summing to 1.0
+    weights: [u32; 3],
+    // Oklab lightness grid, empty for the other modes
+    grid: &'static [f32],
+}
+
+impl LumaLut {
+    pub fn new(mode: Luma) -> Self {
+        let weights = match mode {
+            // Not weighted sums
+            Luma::Average | Luma::OkLab => [0; 3],
+            Luma::Rec601 => [19595, 38470, 7471],
+            Luma::Rec709 => [13933, 46871, 4732],
+        };
+
+        let grid = if mode == Luma::OkLab {
+            oklab_lightness()
+        } else {
+            &[]
+        };
+
+        Self {
+            mode,
+            weights,
+            grid,
+        }
+    }
+
+    // Oklab L interpolated between the eight surrounding grid points
+    #[inline]
+    fn oklab_l(&self, r: u16, g: u16, b: u16) -> f32 {
+        let n = OKLAB_STEPS + 1;
+        let cell = |c: u16| {
+            let f = oklab_coord(c);
+            let i = (f as usize).min(OKLAB_STEPS - 1);
+            (i, f - i as f32)
+        };
+        let ((ri, rt), (gi, gt), (bi, bt)) = (cell(r), cell(g), cell(b));
+        let at = |dr: usize, dg: usize| {
+            let i = (ri + dr) * n * n + (gi + dg) * n + bi;
+            self.grid[i] + (self.grid[i + 1] - self.grid[i]) * bt
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(at(0, 0), at(0, 1), gt),
+            lerp(at(1, 0), at(1, 1), gt),
+            rt,
+        )
+    }
+
+    #[inline]
+    pub fn index(&self, r: u16, g: u16, b: u16) -> usize {
+        match self.mode {
+            // Exact, so 8 bit sources keep their `(r + g + b) / 3` bands
+            Luma::Average => (r as usize + g as usize + b as usize) / 3,
+            Luma::OkLab => (self.oklab_l(r, g, b) * LUMA_MAX as f32)
+                .round()
+                .clamp(0.0, LUMA_MAX as f32) as usize,
+            Luma::Rec601 | Luma::Rec709 => {
+                let sum = self.weights[0] as u64 * r as u64
+                    + self.weights[1] as u64 * g as u64
+                    + self.weights[2] as u64 * b as u64;
+                ((sum + 32768) >> 16).min(LUMA_MAX as u64) as usize
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oklab_round_trip() {
+        for c in [
+            (0, 0, 0),
+            (255, 255, 255),
+            (102, 0, 31),
+            (137, 0, 146),
+            (12, 200, 90),
+        ] {
+            let lab = oklab(c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257);
+            assert_eq!(oklab_to_srgb(lab), c);
+        }
+    }
+
+    #[test]
+    fn luma_extremes() {
+        for mode in Luma::ALL {
+            let lut = LumaLut::new(mode);
+            assert_eq!(lut.index(0, 0, 0), 0, "{mode:?} black");
+            assert_eq!(lut.index(65535, 65535, 65535), LUMA_MAX, "{mode:?} white");
+        }
+    }
+
+    #[test]
+    fn average_of_channels() {
+        let lut = LumaLut::new(Luma::Average);
+        assert_eq!(lut.index(10, 20, 30), 20);
+        assert_eq!(lut.index(65535, 0, 1), 21845);
+    }
+
+    #[test]
+    fn green_brighter_than_blue() {
+        for mode in [Luma::Rec601, Luma::Rec709, Luma::OkLab] {
+            let lut = LumaLut::new(mode);
+            assert!(lut.index(0, 65535, 0) > lut.index(0, 0, 65535), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn oklab_table_matches_exact() {
+        let lut = LumaLut::new(Luma::OkLab);
+        let step = LUMA_MAX as f32 / OKLAB_STEPS as f32;
+        let mut worst = 0.0f32;
+        // Scattered colors plus dark ones, where lightness bends the most
+        for i in 0..4096u64 {
+            let [r, g, b] = [i * 7919, i * 104_729, i * 1_299_709].map(|v| {
+                let v = (v % 65536) as u16;
+                if i % 2 == 0 { v } else { v / 32 }
+            });
+            let exact = oklab(r, g, b)[0] * LUMA_MAX as f32;
+            worst = worst.max((lut.index(r, g, b) as f32 - exact).abs());
+        }
+        assert!(worst <= step, "{worst} off, more than one grid step");
+    }
+}
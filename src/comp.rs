@@ -1,8 +1,9 @@
-use image::Rgb;
+use crate::filt::{MilkBuffer, MilkConfig};
+use crate::pipeline::{Effect, Param, Value};
 use rayon::prelude::*;
 
 // Simulates quantization by reducing color precision across pixels in parallel
-pub fn jpeg_quantization(img: &mut image::ImageBuffer<Rgb<u8>, Vec<u8>>, quality_factor: f32) {
+pub fn jpeg_quantization(img: &mut MilkBuffer, quality_factor: f32) {
     puffin::profile_function!();
 
     let num_levels_f32 = 2.0 + (254.0 * quality_factor.clamp(0.0, 1.0));
@@ -11,21 +12,24 @@ pub fn jpeg_quantization(img: &mut image::ImageBuffer<Rgb<u8>, Vec<u8>>, quality
     let num_levels = num_levels.max(2);
     let num_levels_m1 = num_levels - 1;
 
-    let mut lut = [0u8; 256];
+    let lut: Vec<u16> = (0..=u16::MAX as u32)
+        .map(|input_val| {
+            let level_index = (input_val * num_levels) / 65536;
+            let output_val = (level_index * 65535) / num_levels_m1;
+            output_val.min(65535) as u16
+        })
+        .collect();
 
-    for input_val in 0..=255 {
-        let level_index = (input_val as u32 * num_levels) / 256;
-        let output_val = (level_index * 255) / num_levels_m1;
-        lut[input_val as usize] = output_val.min(255) as u8;
-    }
-
-    img.as_mut().par_iter_mut().for_each(|byte| {
-        *byte = lut[*byte as usize];
+    // Alpha is left as is
+    img.as_mut().par_chunks_exact_mut(4).for_each(|pixel| {
+        for sample in &mut pixel[..3] {
+            *sample = lut[*sample as usize];
+        }
     });
 }
 
-// Simulates blockiness by averaging colors within blocks across rows in parallel
-pub fn jpeg_blockiness(img: &mut image::ImageBuffer<Rgb<u8>, Vec<u8>>, block_size: u32) {
+// Simulates blockiness by averaging colors within square blocks, block rows in parallel
+pub fn jpeg_blockiness(img: &mut MilkBuffer, block_size: u32) {
     puffin::profile_function!();
 
     if block_size <= 1 {
@@ -34,61 +38,742 @@ pub fn jpeg_blockiness(img: &mut image::ImageBuffer<Rgb<u8>, Vec<u8>>, block_siz
 
     let width = img.width() as usize;
     let block_size = block_size as usize;
-    let step = block_size * 3;
+    let blocks = width.div_ceil(block_size);
 
     img.as_mut()
-        .par_chunks_exact_mut(width * 3)
-        .for_each(|row_slice| {
-            let mut chunks = row_slice.chunks_exact_mut(step);
-
-            for block in chunks.by_ref() {
-                let mut sum_r = 0u32;
-                let mut sum_g = 0u32;
-                let mut sum_b = 0u32;
-
-                for pixel in block.chunks_exact(3) {
-                    sum_r += pixel[0] as u32;
-                    sum_g += pixel[1] as u32;
-                    sum_b += pixel[2] as u32;
+        .par_chunks_mut(width * 4 * block_size)
+        .for_each(|band| {
+            // Column reduction: sums of every block over all rows of the band,
+            // blocks at the right and bottom edges are cut short
+            let mut sums = vec![[0u64; 3]; blocks];
+            for row in band.chunks_exact(width * 4) {
+                for (x, pixel) in row.chunks_exact(4).enumerate() {
+                    let sum = &mut sums[x / block_size];
+                    for c in 0..3 {
+                        sum[c] += pixel[c] as u64;
+                    }
+                }
+            }
+
+            let rows = (band.len() / (width * 4)) as u64;
+            let avgs: Vec<[u16; 3]> = sums
+                .iter()
+                .enumerate()
+                .map(|(i, sum)| {
+                    let cols = (width - i * block_size).min(block_size) as u64;
+                    sum.map(|s| (s / (cols * rows)) as u16)
+                })
+                .collect();
+
+            for row in band.chunks_exact_mut(width * 4) {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    pixel[..3].copy_from_slice(&avgs[x / block_size]);
                 }
+            }
+        });
+}
+
+// Simulates codec smearing with a quadtree per `max_size` tile, blocks split down
+// to `min_size` while the standard deviation of any channel exceeds `threshold`
+pub fn quadtree_blockiness(img: &mut MilkBuffer, min_size: u32, max_size: u32, threshold: f32) {
+    puffin::profile_function!();
+
+    let width = img.width() as usize;
+    let min = min_size.max(1) as usize;
+    let max = (max_size as usize).max(min);
+    if width == 0 || max <= 1 {
+        return;
+    }
+
+    img.as_mut()
+        .par_chunks_mut(width * 4 * max)
+        .for_each(|band| {
+            let rows = band.len() / (width * 4);
+            for x in (0..width).step_by(max) {
+                let tile = (x, 0, max.min(width - x), rows);
+                split(band, width, tile, min, threshold);
+            }
+        });
+}
+
+// Flattens `(x, y, w, h)` of `band` to its average or recurses into its quarters
+fn split(
+    band: &mut [u16],
+    width: usize,
+    (x, y, w, h): (usize, usize, usize, usize),
+    min: usize,
+    threshold: f32,
+) {
+    let n = (w * h) as f64;
+    let mut sum = [0.0f64; 3];
+    let mut squares = [0.0f64; 3];
+    for row in y..y + h {
+        for pixel in band[(row * width + x) * 4..(row * width + x + w) * 4].chunks_exact(4) {
+            for c in 0..3 {
+                let v = pixel[c] as f64 / 65535.0;
+                sum[c] += v;
+                squares[c] += v * v;
+            }
+        }
+    }
+    let mean = sum.map(|s| s / n);
+    let variance = (0..3)
+        .map(|c| squares[c] / n - mean[c] * mean[c])
+        .fold(0.0, f64::max);
+
+    if w.max(h) >= 2 * min && variance > (threshold as f64).powi(2) {
+        let (hw, hh) = (w.div_ceil(2), h.div_ceil(2));
+        for (qx, qw) in [(x, hw), (x + hw, w - hw)] {
+            for (qy, qh) in [(y, hh), (y + hh, h - hh)] {
+                if qw > 0 && qh > 0 {
+                    split(band, width, (qx, qy, qw, qh), min, threshold);
+                }
+            }
+        }
+        return;
+    }
+
+    let avg = mean.map(|m| (m * 65535.0).round() as u16);
+    for row in y..y + h {
+        for pixel in band[(row * width + x) * 4..(row * width + x + w) * 4].chunks_exact_mut(4) {
+            pixel[..3].copy_from_slice(&avg);
+        }
+    }
+}
+
+// Full range BT.601 YCbCr of 16 bit RGB, in 8 bit units with chroma centered on 0
+fn to_ycbcr(p: &[u16]) -> [f32; 3] {
+    let [r, g, b] = [0, 1, 2].map(|c| p[c] as f32 / 257.0);
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.168_736 * r - 0.331_264 * g + 0.5 * b,
+        0.5 * r - 0.418_688 * g - 0.081_312 * b,
+    ]
+}
+
+fn from_ycbcr([l, cb, cr]: [f32; 3], p: &mut [u16]) {
+    let rgb = [
+        l + 1.402 * cr,
+        l - 0.344_136 * cb - 0.714_136 * cr,
+        l + 1.772 * cb,
+    ];
+    for c in 0..3 {
+        p[c] = (rgb[c] * 257.0).round().clamp(0.0, 65535.0) as u16;
+    }
+}
+
+// Standard JPEG quantization tables, Annex K of the spec
+#[rustfmt::skip]
+const LUMA_TABLE: [f32; 64] = [
+    16.0, 11.0, 10.0, 16.0, 24.0, 40.0, 51.0, 61.0,
+    12.0, 12.0, 14.0, 19.0, 26.0, 58.0, 60.0, 55.0,
+    14.0, 13.0, 16.0, 24.0, 40.0, 57.0, 69.0, 56.0,
+    14.0, 17.0, 22.0, 29.0, 51.0, 87.0, 80.0, 62.0,
+    18.0, 22.0, 37.0, 56.0, 68.0, 109.0, 103.0, 77.0,
+    24.0, 35.0, 55.0, 64.0, 81.0, 104.0, 113.0, 92.0,
+    49.0, 64.0, 78.0, 87.0, 103.0, 121.0, 120.0, 101.0,
+    72.0, 92.0, 95.0, 98.0, 112.0, 100.0, 103.0, 99.0,
+];
+#[rustfmt::skip]
+const CHROMA_TABLE: [f32; 64] = [
+    17.0, 18.0, 24.0, 47.0, 99.0, 99.0, 99.0, 99.0,
+    18.0, 21.0, 26.0, 66.0, 99.0, 99.0, 99.0, 99.0,
+    24.0, 26.0, 56.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+    47.0, 66.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+    99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+    99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+    99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+    99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0, 99.0,
+];
+
+// Table scaled to `quality` in 1..=100 the way libjpeg does
+fn scaled_table(base: &[f32; 64], quality: u32) -> [f32; 64] {
+    let quality = quality.clamp(1, 100) as f32;
+    let scale = if quality < 50.0 {
+        5000.0 / quality
+    } else {
+        200.0 - 2.0 * quality
+    };
+    base.map(|q| ((q * scale + 50.0) / 100.0).floor().clamp(1.0, 255.0))
+}
+
+// Orthonormal DCT-II basis, `basis[u][x]`
+fn dct_basis() -> [[f32; 8]; 8] {
+    std::array::from_fn(|u| {
+        let norm = if u == 0 { (1.0f32 / 8.0).sqrt() } else { 0.5 };
+        std::array::from_fn(|x| {
+            norm * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos()
+        })
+    })
+}
+
+// Separable 8x8 transform, forward with `inverse` false
+fn dct(block: &mut [f32; 64], basis: &[[f32; 8]; 8], inverse: bool) {
+    let k = |u: usize, x: usize| if inverse { basis[x][u] } else { basis[u][x] };
+    let mut tmp = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            tmp[y * 8 + u] = (0..8).map(|x| k(u, x) * block[y * 8 + x]).sum();
+        }
+    }
+    for u in 0..8 {
+        for v in 0..8 {
+            block[v * 8 + u] = (0..8).map(|y| k(v, y) * tmp[y * 8 + u]).sum();
+        }
+    }
+}
+
+// Simulates JPEG by quantizing the 8x8 DCT of YCbCr planes, block rows in parallel
+pub fn jpeg_dct(img: &mut MilkBuffer, quality: u32) {
+    puffin::profile_function!();
+
+    let width = img.width() as usize;
+    if width == 0 {
+        return;
+    }
+    let tables = [
+        scaled_table(&LUMA_TABLE, quality),
+        scaled_table(&CHROMA_TABLE, quality),
+        scaled_table(&CHROMA_TABLE, quality),
+    ];
+    let basis = dct_basis();
+
+    img.as_mut().par_chunks_mut(width * 4 * 8).for_each(|band| {
+        let rows = band.len() / (width * 4);
+        for bx in (0..width).step_by(8) {
+            // Edge pixels repeat into the parts of the block outside the image
+            let at = |i: usize| {
+                let (x, y) = ((bx + i % 8).min(width - 1), (i / 8).min(rows - 1));
+                (y * width + x) * 4
+            };
 
-                let avg_r = (sum_r / block_size as u32) as u8;
-                let avg_g = (sum_g / block_size as u32) as u8;
-                let avg_b = (sum_b / block_size as u32) as u8;
+            let mut planes = [[0.0; 64]; 3];
+            for i in 0..64 {
+                let [l, cb, cr] = to_ycbcr(&band[at(i)..at(i) + 3]);
+                planes[0][i] = l - 128.0;
+                planes[1][i] = cb;
+                planes[2][i] = cr;
+            }
 
-                for pixel in block.chunks_exact_mut(3) {
-                    pixel[0] = avg_r;
-                    pixel[1] = avg_g;
-                    pixel[2] = avg_b;
+            for (plane, table) in planes.iter_mut().zip(&tables) {
+                dct(plane, &basis, false);
+                for (coef, q) in plane.iter_mut().zip(table) {
+                    *coef = (*coef / q).round() * q;
                 }
+                dct(plane, &basis, true);
             }
 
-            let tail = chunks.into_remainder();
-            if !tail.is_empty() {
-                let len_bytes = tail.len();
-                let pixel_count = (len_bytes / 3) as u32;
+            let [l, cb, cr] = &planes;
+            for (i, ((l, cb), cr)) in l.iter().zip(cb).zip(cr).enumerate() {
+                let (x, y) = (bx + i % 8, i / 8);
+                if x >= width || y >= rows {
+                    continue;
+                }
+                let o = (y * width + x) * 4;
+                from_ycbcr([l + 128.0, *cb, *cr], &mut band[o..o + 3]);
+            }
+        }
+    });
+}
+
+// Round trips through the real JPEG codec `cycles` times, the block grid moves
+// by `shift` pixels every cycle so the damage compounds instead of settling
+pub fn jpeg_generations(
+    img: &mut MilkBuffer,
+    quality: u8,
+    cycles: u32,
+    shift: u32,
+) -> Result<(), image::ImageError> {
+    puffin::profile_function!();
 
-                if pixel_count > 0 {
-                    let mut sum_r = 0u32;
-                    let mut sum_g = 0u32;
-                    let mut sum_b = 0u32;
+    let (width, height) = img.dimensions();
+    for cycle in 0..cycles {
+        let d = cycle * shift % 8;
+        let rgb = image::RgbImage::from_fn(width + d, height + d, |x, y| {
+            let p = img.get_pixel(x.saturating_sub(d), y.saturating_sub(d));
+            image::Rgb([0, 1, 2].map(|c| ((p[c] as u32 + 128) / 257) as u8))
+        });
+
+        let mut data = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, quality)
+            .encode_image(&rgb)?;
+        let decoded =
+            image::load_from_memory_with_format(&data, image::ImageFormat::Jpeg)?.into_rgb8();
 
-                    for pixel in tail.chunks_exact(3) {
-                        sum_r += pixel[0] as u32;
-                        sum_g += pixel[1] as u32;
-                        sum_b += pixel[2] as u32;
+        let row_len = (width + d) as usize * 3;
+        img.as_mut()
+            .par_chunks_exact_mut(width as usize * 4)
+            .zip(decoded.as_raw().par_chunks_exact(row_len).skip(d as usize))
+            .for_each(|(row, src)| {
+                for (pixel, p) in row
+                    .chunks_exact_mut(4)
+                    .zip(src.chunks_exact(3).skip(d as usize))
+                {
+                    for c in 0..3 {
+                        pixel[c] = p[c] as u16 * 257;
                     }
+                }
+            });
+    }
+    Ok(())
+}
 
-                    let avg_r = (sum_r / pixel_count) as u8;
-                    let avg_g = (sum_g / pixel_count) as u8;
-                    let avg_b = (sum_b / pixel_count) as u8;
+/// Chroma resolution of the subsampled image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsampling {
+    /// Half width and half height
+    S420,
+    /// Half width
+    S422,
+    /// Quarter width
+    S411,
+}
 
-                    for pixel in tail.chunks_exact_mut(3) {
-                        pixel[0] = avg_r;
-                        pixel[1] = avg_g;
-                        pixel[2] = avg_b;
+impl Subsampling {
+    pub const ALL: [Self; 3] = [Self::S420, Self::S422, Self::S411];
+    /// Labels in the order of `ALL`
+    pub const NAMES: [&'static str; 3] = ["4:2:0", "4:2:2", "4:1:1"];
+
+    // Pixels per chroma sample across and down
+    fn factors(self) -> (usize, usize) {
+        match self {
+            Self::S420 => (2, 2),
+            Self::S422 => (2, 1),
+            Self::S411 => (4, 1),
+        }
+    }
+}
+
+// Simulates chroma subsampling, color bleeds over edges while brightness stays sharp
+pub fn chroma_subsampling(img: &mut MilkBuffer, scheme: Subsampling, bilinear: bool) {
+    puffin::profile_function!();
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+    let (fx, fy) = scheme.factors();
+    let (cw, ch) = (width.div_ceil(fx), height.div_ceil(fy));
+
+    // Average chroma of every cell, cells at the edges are cut short
+    let raw = img.as_raw();
+    let mut chroma = vec![[0.0f32; 2]; cw * ch];
+    chroma
+        .par_chunks_exact_mut(cw)
+        .enumerate()
+        .for_each(|(cy, row)| {
+            let ys = cy * fy..((cy + 1) * fy).min(height);
+            for (cx, cell) in row.iter_mut().enumerate() {
+                let xs = cx * fx..((cx + 1) * fx).min(width);
+                let n = (xs.len() * ys.len()) as f32;
+                for y in ys.clone() {
+                    for x in xs.clone() {
+                        let o = (y * width + x) * 4;
+                        let [_, cb, cr] = to_ycbcr(&raw[o..o + 3]);
+                        cell[0] += cb / n;
+                        cell[1] += cr / n;
                     }
                 }
             }
         });
+
+    let at = |x: usize, y: usize| chroma[y.min(ch - 1) * cw + x.min(cw - 1)];
+    // Chroma samples sit in the middle of their cells
+    let sample = |x: usize, y: usize| -> [f32; 2] {
+        if !bilinear {
+            return at(x / fx, y / fy);
+        }
+        let sx = ((x as f32 + 0.5) / fx as f32 - 0.5).max(0.0);
+        let sy = ((y as f32 + 0.5) / fy as f32 - 0.5).max(0.0);
+        let (x0, y0) = (sx as usize, sy as usize);
+        let (tx, ty) = (sx.fract(), sy.fract());
+        let lerp = |a: [f32; 2], b: [f32; 2], t: f32| [0, 1].map(|c| a[c] + (b[c] - a[c]) * t);
+        lerp(
+            lerp(at(x0, y0), at(x0 + 1, y0), tx),
+            lerp(at(x0, y0 + 1), at(x0 + 1, y0 + 1), tx),
+            ty,
+        )
+    };
+
+    img.as_mut()
+        .par_chunks_exact_mut(width * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let [cb, cr] = sample(x, y);
+                from_ycbcr([to_ycbcr(pixel)[0], cb, cr], pixel);
+            }
+        });
+}
+
+/// Color precision loss, stronger with higher `compression`.
+#[derive(Default)]
+pub struct Quantize {
+    /// 0..=100, 0 leaves the image untouched
+    pub compression: u32,
+}
+
+impl Effect for Quantize {
+    fn name(&self) -> &'static str {
+        "quantize"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        if self.compression > 0 {
+            let quality_factor = (100.0 - self.compression as f32) / 100.0;
+            jpeg_quantization(img, quality_factor.max(0.05));
+        }
+    }
+
+    fn compression(&self) -> Option<u32> {
+        Some(self.compression)
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![("compression", Value::U32(self.compression))]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![("compression", Param::U32(&mut self.compression, 0..=100))]
+    }
+}
+
+/// JPEG compression with ringing and mosquito noise, stronger with higher `compression`.
+#[derive(Default)]
+pub struct Jpeg {
+    /// 0..=100, 0 leaves the image untouched
+    pub compression: u32,
+}
+
+impl Effect for Jpeg {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        if self.compression > 0 {
+            jpeg_dct(img, 100 - self.compression);
+        }
+    }
+
+    fn compression(&self) -> Option<u32> {
+        Some(self.compression)
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![("compression", Value::U32(self.compression))]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![("compression", Param::U32(&mut self.compression, 0..=100))]
+    }
+}
+
+/// Generation loss of an image saved as JPEG over and over.
+pub struct Generations {
+    /// JPEG quality of every save, 1..=100
+    pub quality: u32,
+    pub cycles: u32,
+    /// Pixels the block grid moves between saves
+    pub shift: u32,
+}
+
+impl Default for Generations {
+    fn default() -> Self {
+        Self {
+            quality: 60,
+            cycles: 10,
+            shift: 1,
+        }
+    }
+}
+
+impl Effect for Generations {
+    fn name(&self) -> &'static str {
+        "generations"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        let quality = self.quality.clamp(1, 100) as u8;
+        if let Err(e) = jpeg_generations(img, quality, self.cycles, self.shift) {
+            log::warn!("jpeg round trip failed: {e}");
+        }
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("quality", Value::U32(self.quality)),
+            ("cycles", Value::U32(self.cycles)),
+            ("shift", Value::U32(self.shift)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("quality", Param::U32(&mut self.quality, 1..=100)),
+            ("cycles", Param::U32(&mut self.cycles, 1..=50)),
+            ("shift", Param::U32(&mut self.shift, 0..=7)),
+        ]
+    }
+}
+
+/// Chroma stored at lower resolution than brightness.
+pub struct Chroma {
+    /// Index into `Subsampling::ALL`
+    pub scheme: u32,
+    /// Smooth upsampling instead of repeating each chroma sample
+    pub bilinear: bool,
+}
+
+impl Default for Chroma {
+    fn default() -> Self {
+        Self {
+            scheme: 0,
+            bilinear: true,
+        }
+    }
+}
+
+impl Effect for Chroma {
+    fn name(&self) -> &'static str {
+        "chroma"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        let scheme = Subsampling::ALL[(self.scheme as usize).min(Subsampling::ALL.len() - 1)];
+        chroma_subsampling(img, scheme, self.bilinear);
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("scheme", Value::Choice(self.scheme, &Subsampling::NAMES)),
+            ("bilinear", Value::Bool(self.bilinear)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            (
+                "scheme",
+                Param::Choice(&mut self.scheme, &Subsampling::NAMES),
+            ),
+            ("bilinear", Param::Bool(&mut self.bilinear)),
+        ]
+    }
+}
+
+/// Square blocks of `size` pixels flattened to their average. Adaptive blocks
+/// start at `size` and split down to `min_size` where the colors vary.
+pub struct Blocks {
+    /// 0 grows the blocks with the compression of the stage before
+    pub size: u32,
+    pub adaptive: bool,
+    pub min_size: u32,
+    /// Standard deviation of a channel, 0..=1, above which adaptive blocks split
+    pub threshold: f32,
+    // Compression handed over by the pipeline for the automatic size
+    compression: u32,
+}
+
+impl Default for Blocks {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            adaptive: false,
+            min_size: 2,
+            threshold: 0.05,
+            compression: 0,
+        }
+    }
+}
+
+impl Blocks {
+    // Up to 8 pixels at full compression when `size` is automatic
+    fn block_size(&self) -> u32 {
+        if self.size == 0 {
+            ((self.compression as f32 / 100.0 * 7.0).max(1.0) as u32).min(8)
+        } else {
+            self.size
+        }
+    }
+}
+
+impl Effect for Blocks {
+    fn name(&self) -> &'static str {
+        "blocks"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        if self.adaptive {
+            // Minimum never above the largest block, or there is nothing to split
+            let size = self.block_size();
+            quadtree_blockiness(img, self.min_size.min(size), size, self.threshold);
+        } else {
+            jpeg_blockiness(img, self.block_size());
+        }
+    }
+
+    fn follow_compression(&mut self, compression: u32) {
+        self.compression = compression;
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("size", Value::U32(self.size)),
+            ("adaptive", Value::Bool(self.adaptive)),
+            ("min_size", Value::U32(self.min_size)),
+            ("threshold", Value::F32(self.threshold)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("size", Param::U32(&mut self.size, 0..=64)),
+            ("adaptive", Param::Bool(&mut self.adaptive)),
+            ("min_size", Param::U32(&mut self.min_size, 1..=64)),
+            ("threshold", Param::F32(&mut self.threshold, 0.0..=0.5)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_square() {
+        // 5x3 image cut into 2x2 blocks, edge blocks are smaller
+        let mut img = MilkBuffer::from_fn(5, 3, |x, y| {
+            let v = (y * 5 + x) as u16 * 100;
+            image::Rgba([v, v, v, 65535])
+        });
+        jpeg_blockiness(&mut img, 2);
+        let row = |y| (0..5).map(|x| img.get_pixel(x, y)[0]).collect::<Vec<_>>();
+        assert_eq!(row(0), [300, 300, 500, 500, 650]);
+        assert_eq!(row(1), row(0));
+        assert_eq!(row(2), [1050, 1050, 1250, 1250, 1400]);
+    }
+
+    #[test]
+    fn chroma_bleeds_luma_stays() {
+        // Reddish and bluish columns, each 4:1:1 cell covers all four
+        let red = image::Rgba([40000, 20000, 20000, 65535]);
+        let blue = image::Rgba([20000, 20000, 40000, 65535]);
+        let src = MilkBuffer::from_fn(4, 2, |x, _| if x < 2 { red } else { blue });
+        let mut img = src.clone();
+        chroma_subsampling(&mut img, Subsampling::S411, false);
+
+        for (a, b) in src.pixels().zip(img.pixels()) {
+            assert!(
+                (to_ycbcr(&a.0)[0] - to_ycbcr(&b.0)[0]).abs() < 0.5,
+                "same luma"
+            );
+        }
+        let left = to_ycbcr(&img.get_pixel(0, 0).0);
+        let right = to_ycbcr(&img.get_pixel(3, 1).0);
+        assert!((left[1] - right[1]).abs() < 0.5 && (left[2] - right[2]).abs() < 0.5);
+    }
+
+    #[test]
+    fn generations_compound() {
+        let src = MilkBuffer::from_fn(20, 12, |x, y| {
+            let v = if (x / 3 + y / 5) % 2 == 0 {
+                50000
+            } else {
+                8000
+            };
+            image::Rgba([v, 30000, 65535 - v, 40000])
+        });
+        let diff = |img: &MilkBuffer| -> u64 {
+            img.as_raw()
+                .iter()
+                .zip(src.as_raw())
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum()
+        };
+
+        let mut once = src.clone();
+        jpeg_generations(&mut once, 30, 1, 3).expect("jpeg round trip");
+        let mut many = src.clone();
+        jpeg_generations(&mut many, 30, 8, 3).expect("jpeg round trip");
+        assert!(diff(&many) > diff(&once), "more damage every save");
+        assert!(many.pixels().all(|p| p[3] == 40000), "alpha is kept");
+    }
+
+    #[test]
+    fn auto_block_size_follows_compression() {
+        let mut blocks = Blocks::default();
+        assert_eq!(blocks.block_size(), 1, "no blocks without compression");
+        blocks.follow_compression(100);
+        assert_eq!(blocks.block_size(), 7);
+        blocks.size = 4;
+        assert_eq!(blocks.block_size(), 4, "explicit size wins");
+    }
+
+    #[test]
+    fn quadtree_splits_detail_only() {
+        // Flat left half, checkerboard right half
+        let mut img = MilkBuffer::from_fn(16, 16, |x, y| {
+            let v = if x < 8 || (x + y) % 2 == 0 { 30000 } else { 0 };
+            image::Rgba([v, v, v, 65535])
+        });
+        quadtree_blockiness(&mut img, 2, 16, 0.01);
+
+        let left = img.get_pixel(0, 0)[0];
+        assert!((0..8).all(|x| img.get_pixel(x, 15)[0] == left), "flat side");
+        let right: Vec<u16> = (8..16).map(|x| img.get_pixel(x, 0)[0]).collect();
+        assert_eq!(
+            right, [15000; 8],
+            "checkers average out in the smallest blocks"
+        );
+    }
+
+    #[test]
+    fn adaptive_minimum_clamps_to_size() {
+        let blocks = Blocks {
+            size: 4,
+            adaptive: true,
+            min_size: 8,
+            threshold: 0.0,
+            ..Blocks::default()
+        };
+        let mut img = MilkBuffer::from_fn(8, 4, |x, _| {
+            let v = x as u16 * 1000;
+            image::Rgba([v, v, v, 65535])
+        });
+        blocks.apply(&mut img, crate::filt::MilkImage::new().get_config());
+        let row: Vec<u16> = (0..8).map(|x| img.get_pixel(x, 3)[0]).collect();
+        assert_eq!(row, [1500, 1500, 1500, 1500, 5500, 5500, 5500, 5500]);
+    }
+
+    #[test]
+    fn dct_round_trip() {
+        let basis = dct_basis();
+        let orig: [f32; 64] = std::array::from_fn(|i| (i * 37 % 255) as f32 - 128.0);
+        let mut block = orig;
+        dct(&mut block, &basis, false);
+        assert!(
+            (block[0] - orig.iter().sum::<f32>() / 8.0).abs() < 1e-2,
+            "DC term"
+        );
+        dct(&mut block, &basis, true);
+        assert!(orig.iter().zip(block).all(|(a, b)| (a - b).abs() < 1e-3));
+    }
+
+    #[test]
+    fn flat_color_survives_compression() {
+        let color = image::Rgba([40000, 20000, 10000, 65535]);
+        let mut img = MilkBuffer::from_pixel(11, 9, color);
+        jpeg_dct(&mut img, 5);
+        let first = *img.get_pixel(0, 0);
+        assert!(
+            img.pixels().all(|p| *p == first),
+            "no ringing without edges"
+        );
+
+        let mut img = MilkBuffer::from_pixel(11, 9, color);
+        jpeg_dct(&mut img, 50);
+        let p = img.get_pixel(10, 8);
+        assert!((0..3).all(|c| p[c].abs_diff(color[c]) < 3 * 257), "{p:?}");
+    }
 }
@@ -0,0 +1,151 @@
+/// Tone curve over `0..=1`, monotone cubic between control points.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    // Sorted by x, first at x = 0 and last at x = 1
+    points: Vec<(f32, f32)>,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self {
+            points: vec![(0.0, 0.0), (1.0, 1.0)],
+        }
+    }
+}
+
+// Closest two control points may get on x
+const MIN_GAP: f32 = 0.01;
+
+impl Curve {
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.points.iter().all(|&(x, y)| (x - y).abs() < 1e-6)
+    }
+
+    /// Adds control point, returns its index or `None` when too close to another
+    pub fn insert(&mut self, x: f32, y: f32) -> Option<usize> {
+        let i = self.points.partition_point(|p| p.0 < x);
+        if i == 0 || i == self.points.len() {
+            return None;
+        }
+        if x - self.points[i - 1].0 < MIN_GAP || self.points[i].0 - x < MIN_GAP {
+            return None;
+        }
+        self.points.insert(i, (x, y.clamp(0.0, 1.0)));
+        Some(i)
+    }
+
+    /// Removes control point, the end points stay
+    pub fn remove(&mut self, index: usize) {
+        if index > 0 && index + 1 < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+
+    /// Moves control point, staying between its neighbours, end points only move on y
+    pub fn set(&mut self, index: usize, x: f32, y: f32) {
+        let last = self.points.len() - 1;
+        let x = match index {
+            0 => 0.0,
+            i if i == last => 1.0,
+            i => x.clamp(
+                self.points[i - 1].0 + MIN_GAP,
+                self.points[i + 1].0 - MIN_GAP,
+            ),
+        };
+        self.points[index] = (x, y.clamp(0.0, 1.0));
+    }
+
+    // Fritsch-Carlson tangents, keep every segment monotone
+    fn tangents(&self) -> Vec<f32> {
+        let p = &self.points;
+        let secants: Vec<f32> = p
+            .windows(2)
+            .map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0))
+            .collect();
+
+        let mut m: Vec<f32> = (0..p.len())
+            .map(|i| match i {
+                0 => secants[0],
+                i if i == p.len() - 1 => secants[i - 1],
+                i if secants[i - 1] * secants[i] <= 0.0 => 0.0,
+                i => f32::midpoint(secants[i - 1], secants[i]),
+            })
+            .collect();
+
+        for (i, &d) in secants.iter().enumerate() {
+            if d == 0.0 {
+                m[i] = 0.0;
+                m[i + 1] = 0.0;
+                continue;
+            }
+            let (a, b) = (m[i] / d, m[i + 1] / d);
+            let h = a.hypot(b);
+            if h > 3.0 {
+                m[i] = 3.0 * a / h * d;
+                m[i + 1] = 3.0 * b / h * d;
+            }
+        }
+        m
+    }
+
+    /// Samples curve at `size` evenly spaced inputs from 0 to 1
+    pub fn table(&self, size: usize) -> Vec<f32> {
+        let m = self.tangents();
+        let p = &self.points;
+        let mut seg = 0;
+
+        (0..size)
+            .map(|i| {
+                let x = i as f32 / (size - 1).max(1) as f32;
+                while seg + 2 < p.len() && x > p[seg + 1].0 {
+                    seg += 1;
+                }
+                let ((x0, y0), (x1, y1)) = (p[seg], p[seg + 1]);
+                let h = x1 - x0;
+                let t = ((x - x0) / h).clamp(0.0, 1.0);
+                let (t2, t3) = (t * t, t * t * t);
+
+                let y = (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+                    + (t3 - 2.0 * t2 + t) * h * m[seg]
+                    + (-2.0 * t3 + 3.0 * t2) * y1
+                    + (t3 - t2) * h * m[seg + 1];
+                y.clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_monotone() {
+        let mut curve = Curve::default();
+        assert!(curve.is_identity(), "starts straight");
+        assert_eq!(curve.insert(0.3, 0.7), Some(1));
+        assert_eq!(curve.insert(0.305, 0.1), None, "too close");
+        assert_eq!(curve.insert(0.6, 0.75), Some(2));
+
+        let table = curve.table(256);
+        assert!(table.windows(2).all(|w| w[0] <= w[1]), "no overshoot");
+        assert!((table[0], table[255]) == (0.0, 1.0), "ends are kept");
+        let at = |x: f32| table[(x * 255.0).round() as usize];
+        assert!(
+            (at(0.3) - 0.7).abs() < 0.01,
+            "passes through control points"
+        );
+    }
+
+    #[test]
+    fn end_points_stay_put() {
+        let mut curve = Curve::default();
+        curve.set(0, 0.5, 0.2);
+        curve.remove(0);
+        assert_eq!(curve.points(), [(0.0, 0.2), (1.0, 1.0)]);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::smix64::{Rng as _, SplitMix64};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+/// How a mixed band picks between its primary and secondary color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Primary color only
+    Off,
+    /// Pointillism, per pixel coin flip weighted by band mix
+    Random,
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    /// Error diffusion, nearest of the band colors with the error pushed forward
+    Diffusion,
+    /// Like pointillism, but with an evenly spread blue noise texture
+    BlueNoise,
+}
+
+impl Dither {
+    pub const ALL: [Self; 7] = [
+        Self::Off,
+        Self::Random,
+        Self::BlueNoise,
+        Self::Bayer2,
+        Self::Bayer4,
+        Self::Bayer8,
+        Self::Diffusion,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Random => "Pointillism",
+            Self::Bayer2 => "Bayer 2x2",
+            Self::Bayer4 => "Bayer 4x4",
+            Self::Bayer8 => "Bayer 8x8",
+            Self::Diffusion => "Error diffusion",
+            Self::BlueNoise => "Blue noise",
+        }
+    }
+
+    /// Whether the mix ratio follows pixel position inside its band
+    pub fn is_ordered(self) -> bool {
+        matches!(self, Self::Bayer2 | Self::Bayer4 | Self::Bayer8)
+    }
+}
+
+/// Error diffusion kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    FloydSteinberg,
+    Atkinson,
+    JarvisJudiceNinke,
+    Sierra,
+}
+
+impl Kernel {
+    pub const ALL: [Self; 4] = [
+        Self::FloydSteinberg,
+        Self::Atkinson,
+        Self::JarvisJudiceNinke,
+        Self::Sierra,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FloydSteinberg => "Floyd-Steinberg",
+            Self::Atkinson => "Atkinson",
+            Self::JarvisJudiceNinke => "Jarvis-Judice-Ninke",
+            Self::Sierra => "Sierra",
+        }
+    }
+
+    /// `(dx, dy, weight)` taps for a left to right scan and the weight divisor
+    pub fn taps(self) -> (&'static [(isize, usize, f32)], f32) {
+        match self {
+            Self::FloydSteinberg => (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0),
+            // Atkinson drops 2/8 of the error on purpose
+            Self::Atkinson => (
+                &[
+                    (1, 0, 1.0),
+                    (2, 0, 1.0),
+                    (-1, 1, 1.0),
+                    (0, 1, 1.0),
+                    (1, 1, 1.0),
+                    (0, 2, 1.0),
+                ],
+                8.0,
+            ),
+            Self::JarvisJudiceNinke => (
+                &[
+                    (1, 0, 7.0),
+                    (2, 0, 5.0),
+                    (-2, 1, 3.0),
+                    (-1, 1, 5.0),
+                    (0, 1, 7.0),
+                    (1, 1, 5.0),
+                    (2, 1, 3.0),
+                    (-2, 2, 1.0),
+                    (-1, 2, 3.0),
+                    (0, 2, 5.0),
+                    (1, 2, 3.0),
+                    (2, 2, 1.0),
+                ],
+                48.0,
+            ),
+            Self::Sierra => (
+                &[
+                    (1, 0, 5.0),
+                    (2, 0, 3.0),
+                    (-2, 1, 2.0),
+                    (-1, 1, 4.0),
+                    (0, 1, 5.0),
+                    (1, 1, 4.0),
+                    (2, 1, 2.0),
+                    (-1, 2, 2.0),
+                    (0, 2, 3.0),
+                    (1, 2, 2.0),
+                ],
+                32.0,
+            ),
+        }
+    }
+}
+
+/// Tileable threshold map, compared against the probability of the primary color.
+pub struct Thresholds {
+    mask: usize,
+    shift: u32,
+    map: Vec<u32>,
+}
+
+impl Thresholds {
+    /// Bayer matrix of `size`x`size`, `size` must be a power of two
+    pub fn bayer(size: usize) -> Self {
+        debug_assert!(size.is_power_of_two(), "bayer size must be a power of two");
+
+        let mut m = vec![0u32];
+        let mut n = 1;
+        while n < size {
+            let mut next = vec![0u32; n * n * 4];
+            for y in 0..n {
+                for x in 0..n {
+                    let v = m[y * n + x] * 4;
+                    next[y * 2 * n + x] = v;
+                    next[y * 2 * n + x + n] = v + 2;
+                    next[(y + n) * 2 * n + x] = v + 3;
+                    next[(y + n) * 2 * n + x + n] = v + 1;
+                }
+            }
+            m = next;
+            n *= 2;
+        }
+
+        Self::from_ranks(size, &m)
+    }
+
+    /// Blue noise map of `size`x`size` made by void-and-cluster, generated once per size
+    pub fn blue_noise(size: usize) -> Arc<Self> {
+        static CACHE: OnceLock<Mutex<HashMap<usize, Arc<Thresholds>>>> = OnceLock::new();
+
+        let mut cache = CACHE
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache
+            .entry(size)
+            .or_insert_with(|| Arc::new(Self::from_ranks(size, &void_and_cluster(size))))
+            .clone()
+    }
+
+    /// Spreads ranks `0..size*size` evenly over the `u32` range
+    fn from_ranks(size: usize, ranks: &[u32]) -> Self {
+        let levels = (size * size) as u64;
+        let map = ranks
+            .iter()
+            .map(|&r| (((r as u64 * 2 + 1) << 32) / (levels * 2)) as u32)
+            .collect();
+
+        Self {
+            mask: size - 1,
+            shift: size.trailing_zeros(),
+            map,
+        }
+    }
+
+    #[inline]
+    pub fn at(&self, x: usize, y: usize) -> u32 {
+        self.map[((y & self.mask) << self.shift) | (x & self.mask)]
+    }
+}
+
+// Gaussian energy of every pixel from all set pixels, on a torus
+#[derive(Clone)]
+struct Energy {
+    size: usize,
+    kernel: Vec<f32>,
+    values: Vec<f32>,
+}
+
+impl Energy {
+    fn new(size: usize) -> Self {
+        const SIGMA: f32 = 1.5;
+
+        let kernel = (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size, i / size);
+                let dx = x.min(size - x) as f32;
+                let dy = y.min(size - y) as f32;
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect();
+
+        Self {
+            size,
+            kernel,
+            values: vec![0.0; size * size],
+        }
+    }
+
+    fn add(&mut self, p: usize, sign: f32) {
+        let size = self.size;
+        let (px, py) = (p % size, p / size);
+        for qy in 0..size {
+            let ky = (qy + size - py) % size * size;
+            for qx in 0..size {
+                self.values[qy * size + qx] += sign * self.kernel[ky + (qx + size - px) % size];
+            }
+        }
+    }
+
+    // Set pixel with most neighbours
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, true, |a, b| a > b)
+    }
+
+    // Unset pixel with fewest neighbours
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        self.extreme(pattern, false, |a, b| a < b)
+    }
+
+    fn extreme(&self, pattern: &[bool], set: bool, better: fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+        for (i, (&p, &e)) in pattern.iter().zip(&self.values).enumerate() {
+            if p == set && best.is_none_or(|(_, be)| better(e, be)) {
+                best = Some((i, e));
+            }
+        }
+        best.map(|(i, _)| i).unwrap_or_default()
+    }
+}
+
+// Ulichney's void-and-cluster, returns rank of every pixel
+fn void_and_cluster(size: usize) -> Vec<u32> {
+    puffin::profile_function!();
+
+    let n = size * size;
+    let ones = (n / 10).max(1);
+    let mut energy = Energy::new(size);
+    let mut pattern = vec![false; n];
+
+    let mut rng = SplitMix64::from_seed(size as u64);
+    let mut placed = 0;
+    while placed < ones {
+        let p = (rng.gen_u64() % n as u64) as usize;
+        if !pattern[p] {
+            pattern[p] = true;
+            energy.add(p, 1.0);
+            placed += 1;
+        }
+    }
+
+    // Move points from clusters into voids until the pattern is even
+    for _ in 0..n {
+        let cluster = energy.tightest_cluster(&pattern);
+        pattern[cluster] = false;
+        energy.add(cluster, -1.0);
+
+        let void = energy.largest_void(&pattern);
+        pattern[void] = true;
+        energy.add(void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0u32; n];
+
+    let mut thinned = pattern.clone();
+    let mut thinned_energy = energy.clone();
+    for rank in (0..ones).rev() {
+        let cluster = thinned_energy.tightest_cluster(&thinned);
+        thinned[cluster] = false;
+        thinned_energy.add(cluster, -1.0);
+        ranks[cluster] = rank as u32;
+    }
+
+    for rank in ones..n {
+        let void = energy.largest_void(&pattern);
+        pattern[void] = true;
+        energy.add(void, 1.0);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_ranks() {
+        let t = Thresholds::bayer(2);
+        let ranks: Vec<u32> = t.map.iter().map(|v| v >> 30).collect();
+        assert_eq!(ranks, [0, 2, 3, 1]);
+
+        let t = Thresholds::bayer(8);
+        let mut sorted = t.map.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 64, "every threshold is unique");
+    }
+
+    #[test]
+    fn blue_noise_is_permutation() {
+        let mut ranks = void_and_cluster(16);
+        ranks.sort_unstable();
+        assert!(ranks.iter().copied().eq(0..256), "every rank used once");
+    }
+}
@@ -0,0 +1,177 @@
+use crate::filt::{MilkBuffer, MilkConfig};
+use crate::pipeline::{Effect, Param, Value};
+use rayon::prelude::*;
+
+/// CRT and VHS look: soft horizontal blur, shifted color channels, phosphor mask
+/// and dark gaps between scanlines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crt {
+    /// How dark the gaps between scanlines get, 0..=1
+    pub scanlines: f32,
+    /// Scanline pitch in pixels
+    pub pitch: u32,
+    /// How much the phosphor mask dims the other two channels, 0..=1
+    pub mask: f32,
+    /// Dot triads offset every other row pair instead of vertical stripes
+    pub shadow_mask: bool,
+    /// Horizontal offsets of red and blue against green, in pixels
+    pub red_shift: f32,
+    pub blue_shift: f32,
+    /// Horizontal box blur radius
+    pub blur: u32,
+}
+
+impl Default for Crt {
+    fn default() -> Self {
+        Self {
+            scanlines: 0.4,
+            pitch: 3,
+            mask: 0.2,
+            shadow_mask: false,
+            red_shift: 1.0,
+            blue_shift: -1.0,
+            blur: 1,
+        }
+    }
+}
+
+impl Crt {
+    // Brightness of row `y` under the scanlines
+    fn scanline(&self, y: usize) -> f32 {
+        let phase = (y as f32 + 0.5) / self.pitch.max(1) as f32 * std::f32::consts::TAU;
+        1.0 - self.scanlines * (0.5 - 0.5 * phase.cos())
+    }
+
+    // Phosphor lit at `(x, y)`
+    fn phosphor(&self, x: usize, y: usize) -> usize {
+        if self.shadow_mask {
+            (x + (y / 2 % 2) * 2) % 3
+        } else {
+            x % 3
+        }
+    }
+}
+
+// Box blur of radius `r` over one channel of a row
+fn blur_row(src: &[f32], r: usize) -> Vec<f32> {
+    if r == 0 {
+        return src.to_vec();
+    }
+    let w = src.len();
+    let mut prefix = Vec::with_capacity(w + 1);
+    prefix.push(0.0);
+    for &v in src {
+        prefix.push(prefix[prefix.len() - 1] + v);
+    }
+    (0..w)
+        .map(|x| {
+            let (lo, hi) = (x.saturating_sub(r), (x + r + 1).min(w));
+            (prefix[hi] - prefix[lo]) / (hi - lo) as f32
+        })
+        .collect()
+}
+
+// Linear sample at `x`, clamped to the row
+fn sample(row: &[f32], x: f32) -> f32 {
+    let x = x.clamp(0.0, (row.len() - 1) as f32);
+    let (i, t) = (x.floor() as usize, x.fract());
+    let next = row[(i + 1).min(row.len() - 1)];
+    row[i] + (next - row[i]) * t
+}
+
+impl Effect for Crt {
+    fn name(&self) -> &'static str {
+        "crt"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        let width = img.width() as usize;
+        if width == 0 {
+            return;
+        }
+        let shifts = [self.red_shift, 0.0, self.blue_shift];
+
+        img.as_mut()
+            .par_chunks_exact_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let channels: Vec<Vec<f32>> = (0..3)
+                    .map(|c| {
+                        let plane: Vec<f32> = row.chunks_exact(4).map(|p| p[c] as f32).collect();
+                        blur_row(&plane, self.blur as usize)
+                    })
+                    .collect();
+                let line = self.scanline(y);
+
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let lit = self.phosphor(x, y);
+                    for c in 0..3 {
+                        let v = sample(&channels[c], x as f32 - shifts[c]);
+                        let mask = if c == lit { 1.0 } else { 1.0 - self.mask };
+                        pixel[c] = (v * mask * line).round().clamp(0.0, 65535.0) as u16;
+                    }
+                }
+            });
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("scanlines", Value::F32(self.scanlines)),
+            ("pitch", Value::U32(self.pitch)),
+            ("mask", Value::F32(self.mask)),
+            ("shadow_mask", Value::Bool(self.shadow_mask)),
+            ("red_shift", Value::F32(self.red_shift)),
+            ("blue_shift", Value::F32(self.blue_shift)),
+            ("blur", Value::U32(self.blur)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("scanlines", Param::F32(&mut self.scanlines, 0.0..=1.0)),
+            ("pitch", Param::U32(&mut self.pitch, 2..=8)),
+            ("mask", Param::F32(&mut self.mask, 0.0..=1.0)),
+            ("shadow_mask", Param::Bool(&mut self.shadow_mask)),
+            ("red_shift", Param::F32(&mut self.red_shift, -8.0..=8.0)),
+            ("blue_shift", Param::F32(&mut self.blue_shift, -8.0..=8.0)),
+            ("blur", Param::U32(&mut self.blur, 0..=4)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shifts_channels_and_darkens_gaps() {
+        let crt = Crt {
+            scanlines: 0.0,
+            mask: 0.0,
+            red_shift: 2.0,
+            blue_shift: 0.0,
+            blur: 0,
+            ..Crt::default()
+        };
+        let mut img = MilkBuffer::from_fn(8, 1, |x, _| {
+            let v = if x == 3 { 65535 } else { 0 };
+            image::Rgba([v, v, v, 65535])
+        });
+        crt.apply(&mut img, crate::filt::MilkImage::new().get_config());
+        assert_eq!(img.get_pixel(5, 0)[0], 65535, "red moved two pixels right");
+        assert_eq!(img.get_pixel(3, 0)[1], 65535, "green stays");
+
+        let lines = Crt {
+            pitch: 2,
+            scanlines: 1.0,
+            ..crt
+        };
+        assert!(lines.scanline(0) > 0.45 && lines.scanline(0) < 0.55);
+        assert!(
+            lines.scanline(0) == lines.scanline(1),
+            "symmetric within a pitch"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use crate::filt::{MilkBuffer, MilkConfig};
+use crate::pipeline::{Effect, Param, Value};
+use crate::smix64::{Rng as _, SplitMix64};
+use rayon::prelude::*;
+
+/// Film grain. Before the milk mapping it roughens band edges, after it
+/// lays noise over the palette.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grain {
+    /// Noise amplitude, 1 is a quarter of the full range per standard deviation
+    pub strength: f32,
+    /// Grain diameter in pixels
+    pub size: u32,
+    /// Separate noise per channel instead of one gray noise
+    pub chroma: bool,
+    /// Strength multipliers for dark, middle and bright pixels
+    pub shadows: f32,
+    pub midtones: f32,
+    pub highlights: f32,
+    pub seed: u32,
+}
+
+impl Default for Grain {
+    fn default() -> Self {
+        Self {
+            strength: 0.3,
+            size: 1,
+            chroma: false,
+            shadows: 1.0,
+            midtones: 1.0,
+            highlights: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+impl Grain {
+    /// Noise plane with unit-ish deviation, blurred to the grain size
+    fn field(&self, plane: u64, width: usize, height: usize) -> Vec<f32> {
+        let mut noise = vec![0f32; width * height];
+        noise
+            .par_chunks_exact_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let seed = ((self.seed as u64) << 32) ^ (plane << 24) ^ y as u64;
+                let mut rng = SplitMix64::from_seed(seed);
+                for v in row {
+                    // Triangle distribution, deviation 1/sqrt(6), scaled to ~1
+                    let t = rng.gen_u32() as f32 + rng.gen_u32() as f32;
+                    *v = (t / u32::MAX as f32 - 1.0) * 6f32.sqrt();
+                }
+            });
+
+        let r = self.size.saturating_sub(1) as usize / 2;
+        if r == 0 {
+            return noise;
+        }
+        // Averaging (2r+1)^2 samples shrinks the deviation by 2r+1
+        let gain = (2 * r + 1) as f32;
+        let mut noise = crate::mask::box_blur(noise, width, height, r);
+        noise.par_iter_mut().for_each(|v| *v *= gain);
+        noise
+    }
+
+    // Strength at brightness `l`, quadratic blend of the three zones
+    fn response(&self, l: f32) -> f32 {
+        let m = 1.0 - l;
+        self.shadows * m * m + self.midtones * 2.0 * l * m + self.highlights * l * l
+    }
+}
+
+impl Effect for Grain {
+    fn name(&self) -> &'static str {
+        "grain"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, _conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        if w == 0 || self.strength <= 0.0 {
+            return;
+        }
+
+        let planes: Vec<Vec<f32>> = if self.chroma {
+            (0..3).map(|c| self.field(c, w, h)).collect()
+        } else {
+            vec![self.field(0, w, h)]
+        };
+        let amount = self.strength * 0.25 * 65535.0;
+
+        img.as_mut()
+            .par_chunks_exact_mut(4)
+            .enumerate()
+            .for_each(|(i, pixel)| {
+                let l = (pixel[0] as f32 + pixel[1] as f32 + pixel[2] as f32) / (3.0 * 65535.0);
+                let amp = amount * self.response(l);
+                for c in 0..3 {
+                    let n = planes[c % planes.len()][i];
+                    pixel[c] = (pixel[c] as f32 + n * amp).round().clamp(0.0, 65535.0) as u16;
+                }
+            });
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("strength", Value::F32(self.strength)),
+            ("size", Value::U32(self.size)),
+            ("chroma", Value::Bool(self.chroma)),
+            ("shadows", Value::F32(self.shadows)),
+            ("midtones", Value::F32(self.midtones)),
+            ("highlights", Value::F32(self.highlights)),
+            ("seed", Value::U32(self.seed)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("strength", Param::F32(&mut self.strength, 0.0..=2.0)),
+            ("size", Param::U32(&mut self.size, 1..=15)),
+            ("chroma", Param::Bool(&mut self.chroma)),
+            ("shadows", Param::F32(&mut self.shadows, 0.0..=2.0)),
+            ("midtones", Param::F32(&mut self.midtones, 0.0..=2.0)),
+            ("highlights", Param::F32(&mut self.highlights, 0.0..=2.0)),
+            ("seed", Param::U32(&mut self.seed, 0..=9999)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deviation(v: &[f32]) -> f32 {
+        let mean = v.iter().sum::<f32>() / v.len() as f32;
+        (v.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / v.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn field_is_seeded_and_normalized() {
+        let grain = Grain::default();
+        let a = grain.field(0, 64, 64);
+        assert_eq!(a, grain.field(0, 64, 64), "same seed, same grain");
+        assert_ne!(a, Grain { seed: 1, ..grain }.field(0, 64, 64));
+
+        for size in [1, 5] {
+            let d = deviation(&Grain { size, ..grain }.field(0, 128, 128));
+            assert!((0.8..1.2).contains(&d), "size {size} deviation {d}");
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Texture and display effects for the pipeline, any of them can run before
+//! or after the milk mapping.
+
+pub mod crt;
+pub mod grain;
+
+pub use crt::Crt;
+pub use grain::Grain;
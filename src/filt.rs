@@ -1,18 +1,40 @@
-use image::Rgb;
+use crate::band::{Band, BandError, BandTable};
+use crate::blend::BlendMode;
+use crate::color::{LUMA_MAX, Luma, LumaLut};
+use crate::curve::Curve;
+use crate::dither::{Dither, Kernel, Thresholds};
+use crate::mask::{KeyMask, Mask};
+use crate::nearest::{self, Mapping};
+use crate::palette::Palette;
+use crate::pipeline::{Effect, Pipeline};
+use crate::smix64::{Rng as _, SplitMix64};
+use image::Rgba;
 use rayon::prelude::*;
+use std::sync::Arc;
+
+/// Working image, every source is widened to 16 bits per channel
+pub type MilkBuffer = image::ImageBuffer<Rgba<u16>, Vec<u16>>;
 
 pub struct MilkImage {
-    img: Option<image::ImageBuffer<Rgb<u8>, Vec<u8>>>,
-    pub processed: Option<image::ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    img: Option<MilkBuffer>,
+    /// Pipeline output before masking
+    filtered: Option<MilkBuffer>,
+    pub processed: Option<MilkBuffer>,
+    /// Where effects apply, everywhere when `None`
+    pub mask: Option<Mask>,
     conf: MilkConfig,
+    pipeline: Pipeline,
 }
 
 impl MilkImage {
     pub fn new() -> Self {
         Self {
             img: None,
+            filtered: None,
             processed: None,
+            mask: None,
             conf: MilkConfig::new(),
+            pipeline: Pipeline::new(),
         }
     }
 
@@ -31,10 +53,24 @@ impl MilkImage {
         };
 
         let img = {
-            puffin::profile_scope!("s_convert_to_rgb8");
-            img.into_rgb8()
+            puffin::profile_scope!("s_convert_to_rgba16");
+            img.into_rgba16()
         };
         self.img = Some(img);
+        self.mask = None;
+    }
+
+    /// Processed image narrowed to 8 bits for display and export
+    pub fn processed_rgba8(&self) -> Option<image::RgbaImage> {
+        puffin::profile_function!();
+
+        let img = self.processed.as_ref()?;
+        let raw = img
+            .as_raw()
+            .par_iter()
+            .map(|&v| ((v as u32 + 128) / 257) as u8)
+            .collect();
+        image::RgbaImage::from_raw(img.width(), img.height(), raw)
     }
 
     pub fn process(&mut self) {
@@ -45,189 +81,593 @@ impl MilkImage {
             self.img.clone().unwrap()
         };
 
-        let mut img = if self.conf.comp > 0 {
-            puffin::profile_scope!("s_compress");
+        self.pipeline.run(&mut img, &self.conf);
 
-            let quality_factor = (100.0 - self.conf.comp as f32) / 100.0;
-            let block_size = if self.conf.block_size == 0 {
-                (((self.conf.comp as f32 / 100.0) * 7.0).max(1.0) as u32).min(8)
-            } else {
-                self.conf.block_size
-            };
+        self.filtered = Some(img);
+        self.composite();
+    }
 
-            if self.conf.quant {
-                crate::comp::jpeg_quantization(&mut img, quality_factor.max(0.05));
-            }
-            if self.conf.block {
-                crate::comp::jpeg_blockiness(&mut img, block_size);
-            }
-            img
-        } else {
-            img
-        };
+    /// Blends and masks pipeline output against the source, cheap enough to run while painting
+    pub fn composite(&mut self) {
+        puffin::profile_function!();
 
-        let width = img.width() as usize;
-        let color_map = [
-            [(0u8, 0u8, 0u8), (102u8, 0u8, 31u8), (137u8, 0u8, 146u8)],
-            [(0u8, 0u8, 0u8), (92u8, 36u8, 60u8), (203u8, 43u8, 43u8)],
-        ];
-        let colors = if self.conf.alt {
-            color_map[1]
-        } else {
-            color_map[0]
+        let (Some(src), Some(filtered)) = (&self.img, &self.filtered) else {
+            return;
         };
-        let chance = if self.conf.pointism { 0.7 } else { 1.0 };
-        let (thr_mid1, thr_mid2) = if self.conf.alt { (90, 150) } else { (120, 200) };
-
-        #[derive(Clone, Copy)]
-        struct Action {
-            c1: (u8, u8, u8),
-            c2: (u8, u8, u8),
-            do_rng: bool,
+        let mut img = filtered.clone();
+
+        crate::blend::blend(&mut img, src, self.conf.blend, self.conf.opacity);
+
+        let painted = self.mask.as_ref().map(|m| m.weights(self.conf.feather));
+        let keyed = self.conf.key.weights(src, self.conf.luma);
+        if let Some(weights) = crate::mask::combine(painted, keyed) {
+            crate::mask::composite(&mut img, src, &weights);
         }
 
-        let mut lut = [Action {
-            c1: (0, 0, 0),
-            c2: (0, 0, 0),
-            do_rng: false,
-        }; 766];
+        self.apply_alpha(&mut img);
+        self.processed = Some(img);
+    }
 
-        for sum in 0..=765 {
-            let avg = sum / 3;
+    /// Size of the loaded image
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.img.as_ref().map(MilkBuffer::dimensions)
+    }
 
-            let (col_primary, col_secondary, use_chance) = if avg <= 25 {
-                let c = if let Some(i) = self.conf.s1 {
-                    colors[i]
-                } else {
-                    colors[0]
-                };
-                (c, c, false)
-            } else if avg <= 70 {
-                if let Some(i) = self.conf.s2 {
-                    (colors[i], colors[i], false)
-                } else if self.conf.eff == 1 {
-                    (colors[1], colors[0], true)
-                } else {
-                    (colors[0], colors[1], true)
-                }
-            } else if avg < thr_mid1 {
-                if let Some(i) = self.conf.s3 {
-                    (colors[i], colors[i], false)
-                } else if self.conf.eff == 1 {
-                    (colors[0], colors[0], false)
-                } else {
-                    (colors[1], colors[0], true)
-                }
-            } else if avg < thr_mid2 {
-                if let Some(i) = self.conf.s4 {
-                    (colors[i], colors[i], false)
-                } else if self.conf.eff == 1 {
-                    (colors[0], colors[1], true)
-                } else {
-                    (colors[1], colors[1], false)
-                }
-            } else if avg < 230 {
-                if let Some(i) = self.conf.s5 {
-                    (colors[i], colors[i], false)
-                } else if self.conf.eff == 1 {
-                    (colors[2], colors[2], false)
-                } else {
-                    (colors[2], colors[1], true)
-                }
-            } else {
-                let c = if let Some(i) = self.conf.s6 {
-                    colors[i]
-                } else {
-                    colors[2]
-                };
-                (c, c, false)
-            };
+    fn apply_alpha(&self, img: &mut MilkBuffer) {
+        puffin::profile_function!();
 
-            lut[sum as usize] = Action {
-                c1: col_primary,
-                c2: col_secondary,
-                do_rng: use_chance && chance != 1.0,
-            };
+        match self.conf.alpha {
+            AlphaMode::Keep => {}
+            AlphaMode::Threshold => {
+                let thr = self.conf.alpha_threshold as u16 * 257;
+                img.as_mut().par_chunks_exact_mut(4).for_each(|pixel| {
+                    pixel[3] = if pixel[3] >= thr { u16::MAX } else { 0 };
+                });
+            }
+            AlphaMode::Mask => {
+                let Some(src) = &self.img else { return };
+                img.as_mut()
+                    .par_chunks_exact_mut(4)
+                    .zip(src.as_raw().par_chunks_exact(4))
+                    .for_each(|(pixel, orig)| {
+                        let a = orig[3] as u64;
+                        for c in 0..3 {
+                            pixel[c] =
+                                ((pixel[c] as u64 * a + orig[c] as u64 * (65535 - a) + 32767)
+                                    / 65535) as u16;
+                        }
+                        pixel[3] = u16::MAX;
+                    });
+            }
         }
+    }
+
+    pub fn get_config(&mut self) -> &mut MilkConfig {
+        &mut self.conf
+    }
+
+    pub fn get_pipeline(&mut self) -> &mut Pipeline {
+        &mut self.pipeline
+    }
+}
 
-        let chance = (chance * u32::MAX as f32) as u32;
+/// Palette mapping stage, configured by [`MilkConfig`].
+pub struct Milk;
 
-        if self.conf.enabled {
-            puffin::profile_scope!("s_apply_filter");
+impl Effect for Milk {
+    fn name(&self) -> &'static str {
+        "milk"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        let width = img.width() as usize;
+        let dither = conf.dither;
 
+        let mapper = conf.build_mapper();
+
+        let thresholds = match dither {
+            Dither::Bayer2 => Some(Arc::new(Thresholds::bayer(2))),
+            Dither::Bayer4 => Some(Arc::new(Thresholds::bayer(4))),
+            Dither::Bayer8 => Some(Arc::new(Thresholds::bayer(8))),
+            Dither::BlueNoise => Some(Thresholds::blue_noise(conf.noise_size)),
+            Dither::Off | Dither::Random | Dither::Diffusion => None,
+        };
+
+        let seed = conf.seed;
+        // Seed also moves threshold textures around
+        let shift = ((seed & 0xff) as usize, ((seed >> 8) & 0xff) as usize);
+
+        if dither == Dither::Diffusion {
+            diffuse(img.as_mut(), width, &mapper, conf.kernel, conf.strength);
+        } else {
             img.as_mut()
-                .par_chunks_mut(width * 3)
+                .par_chunks_mut(width * 4)
                 .enumerate()
                 .for_each(|(y, row)| {
-                    let mut state = ((width * 3) + y) as u64 ^ 0x123456789abcdef0;
+                    let mut rng = SplitMix64::from_seed(((width * 3) + y) as u64 ^ seed);
 
-                    for pixel in row.chunks_exact_mut(3) {
-                        let sum = (pixel[0] as u16 + pixel[1] as u16 + pixel[2] as u16) as usize;
-                        let action = &lut[sum];
+                    for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                        let action = mapper.action(pixel[0], pixel[1], pixel[2]);
 
-                        state = state.wrapping_add(0x9e3779b97f4a7c15);
-                        let mut z = state;
-                        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
-                        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
-                        let rand = ((z ^ (z >> 31)) >> 32) as u32;
+                        let noise = if let Some(t) = &thresholds {
+                            t.at(x + shift.0, y + shift.1)
+                        } else {
+                            rng.gen_u32()
+                        };
 
                         let color = if action.do_rng {
-                            if rand < chance { action.c1 } else { action.c2 }
+                            if noise < action.chance {
+                                action.c1
+                            } else {
+                                action.c2
+                            }
                         } else {
                             action.c1
                         };
 
-                        pixel[0] = color.0;
-                        pixel[1] = color.1;
-                        pixel[2] = color.2;
+                        pixel[..3].copy_from_slice(&color);
                     }
                 });
         }
+    }
+}
 
-        self.processed = Some(img);
+// Error diffusion in one serpentine scan over the whole image, so error flows on
+// through every row. Error is the wanted share of the brighter band color minus
+// the chosen one, solid pixels are exact and drop what reaches them.
+fn diffuse(buf: &mut [u16], width: usize, mapper: &Mapper, kernel: Kernel, strength: f32) {
+    puffin::profile_function!();
+
+    let (taps, div) = kernel.taps();
+    let strength = strength / div;
+    // Current row and two below, padded by 2 on both sides
+    let mut err = vec![vec![0f32; width + 4]; 3];
+
+    for (y, row) in buf.chunks_exact_mut(width * 4).enumerate() {
+        let ltr = y.is_multiple_of(2);
+
+        for i in 0..width {
+            let x = if ltr { i } else { width - 1 - i };
+            let pixel = &mut row[x * 4..x * 4 + 3];
+            let action = mapper.action(pixel[0], pixel[1], pixel[2]);
+            if !action.do_rng {
+                pixel.copy_from_slice(&action.c1);
+                continue;
+            }
+
+            let (dark, bright, share) = if action.flip {
+                (action.c2, action.c1, 1.0 - action.share)
+            } else {
+                (action.c1, action.c2, action.share)
+            };
+            let want = share + err[0][x + 2];
+            let (color, q) = if want >= 0.5 {
+                (bright, 1.0)
+            } else {
+                (dark, 0.0)
+            };
+
+            for &(dx, dy, w) in taps {
+                let dx = if ltr { dx } else { -dx };
+                err[dy][(x as isize + 2 + dx) as usize] += (want - q) * w * strength;
+            }
+
+            pixel.copy_from_slice(&color);
+        }
+
+        err.rotate_left(1);
+        err[2].fill(0.0);
     }
+}
 
-    pub fn get_config(&mut self) -> &mut MilkConfig {
-        &mut self.conf
+// Piecewise linear through `points` sorted by x, flat past both ends
+fn ramp(points: &[(f32, f32)], x: f32) -> f32 {
+    let i = points.partition_point(|p| p.0 <= x);
+    match (points.get(i.wrapping_sub(1)), points.get(i)) {
+        (Some(&(x0, y0)), Some(&(x1, y1))) => y0 + (y1 - y0) * (x - x0) / (x1 - x0),
+        (Some(&(_, y)), None) | (None, Some(&(_, y))) => y,
+        (None, None) => 0.0,
+    }
+}
+
+/// What happens to the alpha channel of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Transparency is copied to the output
+    Keep,
+    /// Pixels become fully opaque or fully transparent at `alpha_threshold`
+    Threshold,
+    /// Output is opaque, alpha decides how much of the effect shows over the source
+    Mask,
+}
+
+impl AlphaMode {
+    pub const ALL: [Self; 3] = [Self::Keep, Self::Threshold, Self::Mask];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Keep => "Keep",
+            Self::Threshold => "Threshold",
+            Self::Mask => "Effect mask",
+        }
+    }
+}
+
+// Palette color widened to 16 bits
+fn wide(c: (u8, u8, u8)) -> [u16; 3] {
+    [c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257]
+}
+
+#[derive(Clone, Copy)]
+struct Action {
+    c1: [u16; 3],
+    c2: [u16; 3],
+    chance: u32,
+    /// Wanted share of `c2`, the position inside the band for error diffusion
+    share: f32,
+    /// `c1` is the brighter color, diffusion measures error towards the brighter one
+    flip: bool,
+    do_rng: bool,
+}
+
+// Actions indexed by brightness for bands, by LUT cell for nearest color
+struct Mapper {
+    actions: Vec<Action>,
+    luma: Option<LumaLut>,
+}
+
+impl Mapper {
+    #[inline]
+    fn action(&self, r: u16, g: u16, b: u16) -> &Action {
+        let i = match &self.luma {
+            Some(luma) => luma.index(r, g, b),
+            None => nearest::cell(r, g, b),
+        };
+        &self.actions[i]
     }
 }
 
 pub struct MilkConfig {
-    pub alt: bool,
-    pub pointism: bool,
-    pub comp: u8,
-
-    pub enabled: bool,
-    pub quant: bool,
-    pub block: bool,
-    pub block_size: u32,
-
-    pub eff: u8,
-    pub s1: Option<usize>,
-    pub s2: Option<usize>,
-    pub s3: Option<usize>,
-    pub s4: Option<usize>,
-    pub s5: Option<usize>,
-    pub s6: Option<usize>,
+    palette: Palette,
+    bands: BandTable,
+    // Middle band thresholds of the built-in palette, used to lay bands out anew
+    mid: (u8, u8),
+    pub luma: Luma,
+    /// Brightness remap in front of the bands
+    pub curve: Curve,
+    pub mapping: Mapping,
+    /// Weight of hue differences for nearest color mapping, 1 is plain distance
+    pub hue_weight: f32,
+    pub dither: Dither,
+    pub kernel: Kernel,
+    /// Share of the quantization error that gets diffused
+    pub strength: f32,
+    /// Blue noise texture side
+    pub noise_size: usize,
+    /// Seed of the pointillism noise, same seed gives same output
+    pub seed: u64,
+    /// Multiplier of band mix probabilities for pointillism and blue noise
+    pub density: f32,
+
+    pub alpha: AlphaMode,
+    pub alpha_threshold: u8,
+    /// Softening radius of painted mask edges in pixels
+    pub feather: u32,
+    /// Mask generated from the source, multiplied with the painted one
+    pub key: KeyMask,
+    pub blend: BlendMode,
+    /// Share of the blended effect over the source, 0..=1
+    pub opacity: f32,
 }
 
+// Middle band thresholds of the built-in palettes
+const MILK_MID: (u8, u8) = (120, 200);
+const ALT_MID: (u8, u8) = (90, 150);
+
 impl MilkConfig {
     fn new() -> Self {
+        let palette = Palette::milk();
         Self {
-            alt: false,
-            pointism: false,
-            comp: 0,
-            enabled: true,
-            quant: true,
-            block: true,
-            block_size: 0,
-            eff: 0,
-            s1: None,
-            s2: None,
-            s3: None,
-            s4: None,
-            s5: None,
-            s6: None,
+            bands: BandTable::spread(palette.colors().len(), MILK_MID),
+            palette,
+            mid: MILK_MID,
+            luma: Luma::Average,
+            curve: Curve::default(),
+            mapping: Mapping::Bands,
+            hue_weight: 1.0,
+            dither: Dither::Off,
+            kernel: Kernel::FloydSteinberg,
+            strength: 1.0,
+            noise_size: 64,
+            seed: 0x123456789abcdef0,
+            density: 1.0,
+            alpha: AlphaMode::Keep,
+            alpha_threshold: 128,
+            feather: 0,
+            key: KeyMask::default(),
+            blend: BlendMode::Normal,
+            opacity: 1.0,
+        }
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn bands(&self) -> &BandTable {
+        &self.bands
+    }
+
+    /// Replaces palette, keeping the band table unless the number of colors changes,
+    /// then bands are laid out anew so every slot is used and none is missing
+    pub fn set_palette(&mut self, palette: Palette) {
+        let resized = palette.colors().len() != self.palette.colors().len();
+        self.palette = palette;
+        if resized {
+            self.bands = self.default_bands();
+        }
+    }
+
+    /// Replaces band table
+    ///
+    /// # Errors
+    /// When the table is invalid for the current palette.
+    pub fn set_bands(&mut self, bands: BandTable) -> Result<(), BandError> {
+        bands.validate(self.palette.colors().len())?;
+        self.bands = bands;
+        Ok(())
+    }
+
+    /// Brightness lookup of the current `luma` mode, its tables are shared and built once
+    pub fn luma_lut(&self) -> LumaLut {
+        LumaLut::new(self.luma)
+    }
+
+    fn build_mapper(&self) -> Mapper {
+        if self.mapping == Mapping::Bands {
+            let luma = self.luma_lut();
+            Mapper {
+                actions: self.build_lut(&luma),
+                luma: Some(luma),
+            }
+        } else {
+            Mapper {
+                actions: self.build_nearest(),
+                luma: None,
+            }
+        }
+    }
+
+    /// Per brightness index colors of the band and probability of the primary one
+    fn build_lut(&self, luma: &LumaLut) -> Vec<Action> {
+        puffin::profile_function!();
+
+        let mut lut = vec![
+            Action {
+                c1: [0; 3],
+                c2: [0; 3],
+                chance: u32::MAX,
+                share: 0.0,
+                flip: false,
+                do_rng: false,
+            };
+            LUMA_MAX + 1
+        ];
+
+        let colors = self.palette.colors();
+        let bands = self.bands.bands();
+        let mut start = 0;
+        let ranges: Vec<(usize, usize)> = bands
+            .iter()
+            .map(|band| {
+                // Band thresholds are 8 bit, 257 is the 8 to 16 bit scale
+                let range = (start * 257, (band.upto as usize * 257 + 256).min(LUMA_MAX));
+                start = band.upto as usize + 1;
+                range
+            })
+            .collect();
+
+        let brightness = |slot: usize| {
+            let c = wide(colors[slot]);
+            luma.index(c[0], c[1], c[2])
+        };
+        // Neighbouring mixed bands over the same two colors form one run
+        let pair = |b: &Band| {
+            (b.mix > 0.0 && b.primary != b.secondary)
+                .then(|| (b.primary.min(b.secondary), b.primary.max(b.secondary)))
+        };
+
+        let mut first = 0;
+        while first < bands.len() {
+            let mut last = first;
+            while pair(&bands[first]).is_some()
+                && bands
+                    .get(last + 1)
+                    .is_some_and(|b| pair(b) == pair(&bands[first]))
+            {
+                last += 1;
+            }
+            let run = &bands[first..=last];
+            let bright = if brightness(run[0].primary) > brightness(run[0].secondary) {
+                run[0].primary
+            } else {
+                run[0].secondary
+            };
+
+            // Share of the brighter color climbs from 0 to 1 over the whole run,
+            // passing the mix of every band at its middle
+            let anchors = pair(&run[0]).map(|_| {
+                let mut anchors = vec![(ranges[first].0 as f32, 0.0)];
+                for (band, &(lo, hi)) in run.iter().zip(&ranges[first..=last]) {
+                    let share = if band.secondary == bright {
+                        band.mix
+                    } else {
+                        1.0 - band.mix
+                    };
+                    anchors.push((f32::midpoint(lo as f32, hi as f32), share));
+                }
+                anchors.push((ranges[last].1 as f32, 1.0));
+                anchors
+            });
+
+            for (band, &(lo, hi)) in run.iter().zip(&ranges[first..=last]) {
+                let (c1, c2) = (wide(colors[band.primary]), wide(colors[band.secondary]));
+
+                for (idx, action) in lut.iter_mut().enumerate().take(hi + 1).skip(lo) {
+                    let even = anchors.as_ref().map_or(1.0, |a| {
+                        let s = ramp(a, idx as f32);
+                        if band.primary == bright { s } else { 1.0 - s }
+                    });
+                    let p1 = if self.dither.is_ordered() {
+                        even
+                    } else {
+                        1.0 - (band.mix * self.density).min(1.0)
+                    };
+
+                    *action = Action {
+                        c1,
+                        c2,
+                        chance: (p1 * u32::MAX as f32) as u32,
+                        share: 1.0 - even,
+                        flip: band.primary == bright && band.primary != band.secondary,
+                        do_rng: self.dither != Dither::Off && band.mix > 0.0,
+                    };
+                }
+            }
+            first = last + 1;
+        }
+
+        if self.curve.is_identity() {
+            return lut;
+        }
+        // Curve remaps brightness before the band lookup
+        self.curve
+            .table(LUMA_MAX + 1)
+            .into_iter()
+            .map(|y| lut[(y * LUMA_MAX as f32).round() as usize])
+            .collect()
+    }
+
+    /// Per LUT cell nearest color, mixed with the second nearest by distance
+    fn build_nearest(&self) -> Vec<Action> {
+        let colors = self.palette.colors();
+        nearest::cached(&self.palette, self.mapping, self.hue_weight)
+            .par_iter()
+            .map(|n| {
+                let p1 = if self.dither.is_ordered() {
+                    1.0 - n.share
+                } else {
+                    1.0 - (n.share * self.density).min(1.0)
+                };
+
+                Action {
+                    c1: wide(colors[n.first]),
+                    c2: wide(colors[n.second]),
+                    chance: (p1 * u32::MAX as f32) as u32,
+                    share: n.share,
+                    flip: false,
+                    do_rng: self.dither != Dither::Off,
+                }
+            })
+            .collect()
+    }
+
+    /// Switches to one of the built-in palettes together with its band layout
+    pub fn set_builtin(&mut self, alt: bool) {
+        let (palette, mid) = if alt {
+            (Palette::alt(), ALT_MID)
+        } else {
+            (Palette::milk(), MILK_MID)
+        };
+        self.mid = mid;
+        self.palette = palette;
+        self.bands = self.default_bands();
+    }
+
+    /// Bands spread over the palette with the layout of the active built-in palette
+    pub fn default_bands(&self) -> BandTable {
+        BandTable::spread(self.palette.colors().len(), self.mid)
+    }
+
+    /// Replaces palette and lays the bands out anew for it
+    pub fn replace_palette(&mut self, palette: Palette) {
+        self.mid = MILK_MID;
+        self.palette = palette;
+        self.bands = self.default_bands();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffusion_keeps_solid_bands() {
+        let mut conf = MilkConfig::new();
+        for gray in [10u16, 150, 240] {
+            let src = MilkBuffer::from_pixel(64, 64, image::Rgba([gray * 257; 4]));
+
+            conf.dither = Dither::Off;
+            let mut solid = src.clone();
+            Milk.apply(&mut solid, &conf);
+
+            conf.dither = Dither::Diffusion;
+            for kernel in Kernel::ALL {
+                conf.kernel = kernel;
+                let mut img = src.clone();
+                Milk.apply(&mut img, &conf);
+                assert!(img == solid, "gray {gray} with {}", kernel.name());
+            }
         }
     }
+
+    #[test]
+    fn palette_size_changes_relayout_bands() {
+        let mut conf = MilkConfig::new();
+        let mut palette = conf.palette().clone();
+        palette.push((255, 255, 255)).expect("room for a color");
+        conf.set_palette(palette.clone());
+        assert_eq!(conf.bands().validate(4), Ok(()));
+        let used = |conf: &MilkConfig, slot| conf.bands().bands().iter().any(|b| b.primary == slot);
+        assert!(used(&conf, 3), "new color gets a band");
+
+        palette.remove(1).expect("enough colors");
+        conf.set_palette(palette);
+        assert_eq!(
+            conf.bands().validate(3),
+            Ok(()),
+            "no band points past the palette"
+        );
+    }
+
+    #[test]
+    fn ordered_dither_ramps_across_bands() {
+        let mut conf = MilkConfig::new();
+        conf.dither = Dither::Bayer8;
+        // Every gray covers a whole 8x8 threshold tile
+        let mut img = MilkBuffer::from_fn(256 * 8, 8, |x, _| {
+            let v = (x / 8) as u16 * 257;
+            Rgba([v, v, v, 65535])
+        });
+        Milk.apply(&mut img, &conf);
+
+        let black: Vec<usize> = (0..256)
+            .map(|g| {
+                (0..64)
+                    .filter(|i| img.get_pixel(g * 8 + i % 8, i / 8).0[..3] == [0, 0, 0])
+                    .count()
+            })
+            .collect();
+        assert_eq!((black[25], black[120]), (64, 0), "solid on both sides");
+        assert!(
+            black.windows(2).all(|w| w[0] >= w[1]),
+            "black coverage never grows with brightness: {black:?}"
+        );
+        assert!(
+            black.windows(2).all(|w| w[0] - w[1] <= 4),
+            "no jumps between bands: {black:?}"
+        );
+    }
 }
@@ -0,0 +1,299 @@
+use crate::color::{LUMA_MAX, LumaLut};
+use crate::filt::{MilkBuffer, MilkConfig};
+use crate::palette::MAX_COLORS;
+use crate::pipeline::{Effect, Param, Value};
+use rayon::prelude::*;
+
+/// Outlines drawn over edges in one palette color. Before milk it traces the
+/// photo, after milk it traces the band borders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ink {
+    /// Edge strength, 0..=1, above which a pixel is inked
+    pub threshold: f32,
+    /// Line width in pixels
+    pub thickness: u32,
+    /// Thin edges and follow them through weaker gradients
+    pub canny: bool,
+    /// Share of `threshold` a Canny edge may fade to and still continue
+    pub low: f32,
+    /// Palette slot of the ink, clamped to the palette
+    pub slot: u32,
+}
+
+impl Default for Ink {
+    fn default() -> Self {
+        Self {
+            threshold: 0.25,
+            thickness: 1,
+            canny: false,
+            low: 0.5,
+            slot: 0,
+        }
+    }
+}
+
+// Clamped pixel access of a single channel plane
+fn at(v: &[f32], w: usize, h: usize, x: isize, y: isize) -> f32 {
+    let x = x.clamp(0, w as isize - 1) as usize;
+    let y = y.clamp(0, h as isize - 1) as usize;
+    v[y * w + x]
+}
+
+// Brightness plane smoothed by a 3x3 gaussian
+fn smoothed(img: &MilkBuffer, luma: &LumaLut) -> Vec<f32> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let gray: Vec<f32> = img
+        .as_raw()
+        .par_chunks_exact(4)
+        .map(|p| luma.index(p[0], p[1], p[2]) as f32 / LUMA_MAX as f32)
+        .collect();
+
+    let mut out = vec![0.0; w * h];
+    out.par_chunks_exact_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, o) in row.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (dy, wy) in [(-1, 1.0), (0, 2.0), (1, 1.0)] {
+                    for (dx, wx) in [(-1, 1.0), (0, 2.0), (1, 1.0)] {
+                        sum += wy * wx * at(&gray, w, h, x as isize + dx, y as isize + dy);
+                    }
+                }
+                *o = sum / 16.0;
+            }
+        });
+    out
+}
+
+// Sobel gradient magnitude, 1.0 for a full black to white step, and direction
+fn sobel(gray: &[f32], w: usize, h: usize) -> Vec<(f32, f32)> {
+    let mut out = vec![(0.0, 0.0); w * h];
+    out.par_chunks_exact_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let p =
+                |dx: isize, dy: isize, x: usize| at(gray, w, h, x as isize + dx, y as isize + dy);
+            for (x, o) in row.iter_mut().enumerate() {
+                let gx = p(1, -1, x) + 2.0 * p(1, 0, x) + p(1, 1, x)
+                    - p(-1, -1, x)
+                    - 2.0 * p(-1, 0, x)
+                    - p(-1, 1, x);
+                let gy = p(-1, 1, x) + 2.0 * p(0, 1, x) + p(1, 1, x)
+                    - p(-1, -1, x)
+                    - 2.0 * p(0, -1, x)
+                    - p(1, -1, x);
+                *o = (gx.hypot(gy) / 4.0, gy.atan2(gx));
+            }
+        });
+    out
+}
+
+// Keeps only the local maxima across the gradient direction
+fn thin(grad: &[(f32, f32)], w: usize, h: usize) -> Vec<f32> {
+    let mag: Vec<f32> = grad.iter().map(|g| g.0).collect();
+    let mut out = vec![0.0; w * h];
+    out.par_chunks_exact_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, o) in row.iter_mut().enumerate() {
+                let (m, angle) = grad[y * w + x];
+                // Direction rounded to one of four neighbour pairs
+                let sector = ((angle.to_degrees() + 202.5) / 45.0) as usize % 4;
+                let (dx, dy) = [(1, 0), (1, 1), (0, 1), (-1, 1)][sector];
+                let (x, y) = (x as isize, y as isize);
+                let a = at(&mag, w, h, x + dx, y + dy);
+                let b = at(&mag, w, h, x - dx, y - dy);
+                *o = if m >= a && m >= b { m } else { 0.0 };
+            }
+        });
+    out
+}
+
+// Strong edges plus weak ones connected to them. Seeds are found in parallel, the
+// flood itself is serial but only visits the strong pixels and the weak pixels
+// reachable from them, each once, which after thinning is a small share of the image.
+fn hysteresis(mag: &[f32], w: usize, high: f32, low: f32) -> Vec<bool> {
+    let mut edges: Vec<bool> = mag.par_iter().map(|&m| m > high).collect();
+    let mut stack: Vec<usize> = edges
+        .par_iter()
+        .enumerate()
+        .filter_map(|(i, &e)| e.then_some(i))
+        .collect();
+
+    while let Some(i) = stack.pop() {
+        let (x, y) = ((i % w) as isize, (i / w) as isize);
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= w as isize {
+                    continue;
+                }
+                let n = ny as usize * w + nx as usize;
+                if n < mag.len() && !edges[n] && mag[n] > low {
+                    edges[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+    }
+    edges
+}
+
+// Grows every edge pixel into a square of `radius`
+fn dilate(edges: &[bool], w: usize, h: usize, radius: usize) -> Vec<bool> {
+    if radius == 0 {
+        return edges.to_vec();
+    }
+
+    let mut wide = vec![false; w * h];
+    wide.par_chunks_exact_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let src = &edges[y * w..(y + 1) * w];
+            for (x, o) in row.iter_mut().enumerate() {
+                *o = src[x.saturating_sub(radius)..(x + radius + 1).min(w)]
+                    .iter()
+                    .any(|&e| e);
+            }
+        });
+
+    let mut out = vec![false; w * h];
+    out.par_chunks_exact_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let rows = y.saturating_sub(radius)..(y + radius + 1).min(h);
+            for (x, o) in row.iter_mut().enumerate() {
+                *o = rows.clone().any(|r| wide[r * w + x]);
+            }
+        });
+    out
+}
+
+impl Effect for Ink {
+    fn name(&self) -> &'static str {
+        "ink"
+    }
+
+    fn apply(&self, img: &mut MilkBuffer, conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        if w == 0 || h == 0 {
+            return;
+        }
+
+        let grad = sobel(&smoothed(img, &conf.luma_lut()), w, h);
+        let edges = if self.canny {
+            hysteresis(
+                &thin(&grad, w, h),
+                w,
+                self.threshold,
+                self.threshold * self.low,
+            )
+        } else {
+            grad.iter().map(|g| g.0 > self.threshold).collect()
+        };
+        let edges = dilate(&edges, w, h, self.thickness.saturating_sub(1) as usize);
+
+        let colors = conf.palette().colors();
+        let c = colors[(self.slot as usize).min(colors.len() - 1)];
+        let ink = [c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257];
+
+        img.as_mut()
+            .par_chunks_exact_mut(4)
+            .zip(edges.par_iter())
+            .filter(|(_, e)| **e)
+            .for_each(|(pixel, _)| pixel[..3].copy_from_slice(&ink));
+    }
+
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("threshold", Value::F32(self.threshold)),
+            ("thickness", Value::U32(self.thickness)),
+            ("canny", Value::Bool(self.canny)),
+            ("low", Value::F32(self.low)),
+            ("slot", Value::U32(self.slot)),
+        ]
+    }
+
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        vec![
+            ("threshold", Param::F32(&mut self.threshold, 0.01..=1.0)),
+            ("thickness", Param::U32(&mut self.thickness, 1..=8)),
+            ("canny", Param::Bool(&mut self.canny)),
+            ("low", Param::F32(&mut self.low, 0.0..=1.0)),
+            (
+                "slot",
+                Param::U32(&mut self.slot, 0..=MAX_COLORS as u32 - 1),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Black left half, white right half
+    fn step(w: usize, h: usize) -> Vec<f32> {
+        (0..w * h)
+            .map(|i| if i % w < w / 2 { 0.0 } else { 1.0 })
+            .collect()
+    }
+
+    #[test]
+    fn finds_step_edge() {
+        let (w, h) = (8, 4);
+        let grad = sobel(&step(w, h), w, h);
+        let row: Vec<bool> = grad[w..2 * w].iter().map(|g| g.0 > 0.5).collect();
+        assert_eq!(row, [false, false, false, true, true, false, false, false]);
+
+        let thinned = thin(&grad, w, h);
+        let edges = hysteresis(&thinned, w, 0.5, 0.25);
+        assert_eq!(
+            edges.iter().filter(|&&e| e).count(),
+            2 * h,
+            "two pixel wide line"
+        );
+    }
+
+    #[test]
+    fn inks_edges_in_slot_color() {
+        let (w, h) = (16, 4);
+        let gray = |x: u32| if x < w / 2 { 0 } else { u16::MAX };
+        let mut img = MilkBuffer::from_fn(w, h, |x, _| {
+            image::Rgba([gray(x), gray(x), gray(x), u16::MAX])
+        });
+        let mut milk = crate::filt::MilkImage::new();
+        let conf = milk.get_config();
+        let ink = Ink {
+            threshold: 0.5,
+            slot: 1,
+            ..Ink::default()
+        };
+        ink.apply(&mut img, conf);
+
+        let c = conf.palette().colors()[1];
+        let color = [c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257];
+        for (x, y, p) in img.enumerate_pixels() {
+            if x == w / 2 - 1 || x == w / 2 {
+                assert_eq!(p.0[..3], color, "edge at {x},{y}");
+            } else {
+                assert_eq!(p.0, [gray(x), gray(x), gray(x), u16::MAX], "{x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn dilation_grows_lines() {
+        let (w, h) = (5, 5);
+        let mut edges = vec![false; w * h];
+        edges[12] = true;
+        let grown = dilate(&edges, w, h, 1);
+        assert_eq!(grown.iter().filter(|&&e| e).count(), 9);
+        assert!(
+            !grown[0] && grown[6] && grown[18],
+            "3x3 square around center"
+        );
+    }
+}
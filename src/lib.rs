@@ -1,7 +1,19 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
+mod adjust;
 mod app;
+mod band;
+mod blend;
+mod color;
 mod comp;
+mod curve;
+mod dither;
+mod effects;
 mod filt;
+mod ink;
+mod mask;
+mod nearest;
+mod palette;
+mod pipeline;
 mod smix64;
 pub use app::MilkApp;
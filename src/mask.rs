@@ -0,0 +1,477 @@
+use crate::color::{self, LUMA_MAX, LumaLut};
+use crate::filt::MilkBuffer;
+use image::{GrayImage, Luma};
+use rayon::prelude::*;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaskError {
+    /// Sidecar could not be read or written
+    Image(String),
+    /// Sidecar does not match the image it is loaded for
+    Size {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+}
+
+impl fmt::Display for MaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(e) => write!(f, "mask image: {e}"),
+            Self::Size { expected, found } => write!(
+                f,
+                "mask is {}x{} but the image is {}x{}",
+                found.0, found.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MaskError {}
+
+/// Painted region where effects apply, 255 is full effect and 0 keeps the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mask {
+    img: GrayImage,
+}
+
+impl Mask {
+    pub fn new(width: u32, height: u32, value: u8) -> Self {
+        Self {
+            img: GrayImage::from_pixel(width, height, Luma([value])),
+        }
+    }
+
+    /// Reads sidecar PNG saved by [`Mask::to_png`] for an image of `width`x`height`
+    ///
+    /// # Errors
+    /// When the data is not an image or its size differs.
+    pub fn from_png(data: &[u8], width: u32, height: u32) -> Result<Self, MaskError> {
+        let img = image::load_from_memory(data)
+            .map_err(|e| MaskError::Image(e.to_string()))?
+            .into_luma8();
+        if img.dimensions() != (width, height) {
+            return Err(MaskError::Size {
+                expected: (width, height),
+                found: img.dimensions(),
+            });
+        }
+        Ok(Self { img })
+    }
+
+    /// Encodes mask as grayscale PNG
+    ///
+    /// # Errors
+    /// When encoding fails.
+    pub fn to_png(&self) -> Result<Vec<u8>, MaskError> {
+        let mut buf = Vec::new();
+        self.img
+            .write_to(&mut std::io::Cursor::new(&mut buf), image::ImageFormat::Png)
+            .map_err(|e| MaskError::Image(e.to_string()))?;
+        Ok(buf)
+    }
+
+    pub fn as_image(&self) -> &GrayImage {
+        &self.img
+    }
+
+    pub fn invert(&mut self) {
+        self.img.as_mut().par_iter_mut().for_each(|v| *v = 255 - *v);
+    }
+
+    /// Round brush dragged from `from` to `to`
+    pub fn stroke(&mut self, from: (f32, f32), to: (f32, f32), radius: f32, value: u8) {
+        let radius = radius.max(0.5);
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let steps = ((dx.hypot(dy) / (radius * 0.5)).ceil() as usize).max(1);
+
+        for i in 0..=steps {
+            let t = i as f32 / steps as f32;
+            self.dab((from.0 + dx * t, from.1 + dy * t), radius, value);
+        }
+    }
+
+    fn dab(&mut self, (cx, cy): (f32, f32), radius: f32, value: u8) {
+        let (w, h) = self.img.dimensions();
+        let x0 = (cx - radius).floor().max(0.0) as u32;
+        let y0 = (cy - radius).floor().max(0.0) as u32;
+        let x1 = ((cx + radius).ceil().max(0.0) as u32).min(w);
+        let y1 = ((cy + radius).ceil().max(0.0) as u32).min(h);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (px, py) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                if px * px + py * py <= radius * radius {
+                    self.img.put_pixel(x, y, Luma([value]));
+                }
+            }
+        }
+    }
+
+    /// Fills rectangle spanned by corners `a` and `b`
+    pub fn fill_rect(&mut self, a: (f32, f32), b: (f32, f32), value: u8) {
+        self.fill_polygon(&[a, (b.0, a.1), b, (a.0, b.1)], value);
+    }
+
+    /// Fills closed polygon, even-odd rule
+    pub fn fill_polygon(&mut self, points: &[(f32, f32)], value: u8) {
+        if points.len() < 3 {
+            return;
+        }
+        let width = self.img.width() as usize;
+
+        self.img
+            .as_mut()
+            .par_chunks_exact_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let sy = y as f32 + 0.5;
+                let mut cross: Vec<f32> = points
+                    .iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .filter(|(a, b)| (a.1 <= sy) != (b.1 <= sy))
+                    .map(|(a, b)| a.0 + (sy - a.1) / (b.1 - a.1) * (b.0 - a.0))
+                    .collect();
+                cross.sort_by(f32::total_cmp);
+
+                for span in cross.chunks_exact(2) {
+                    // Pixel centers inside the span
+                    let x0 = (span[0] - 0.5).ceil().clamp(0.0, width as f32) as usize;
+                    let x1 = (span[1] - 0.5).ceil().clamp(0.0, width as f32) as usize;
+                    row[x0..x1].fill(value);
+                }
+            });
+    }
+
+    /// Per pixel effect weight in `0..=65535`, edges softened over `feather` pixels
+    pub fn weights(&self, feather: u32) -> Vec<u16> {
+        puffin::profile_function!();
+
+        let (w, h) = (self.img.width() as usize, self.img.height() as usize);
+        let mut v: Vec<f32> = self.img.as_raw().iter().map(|&m| m as f32).collect();
+
+        if feather > 0 {
+            // Two box passes make a tent, close enough to a gaussian for masks
+            let r = (feather as usize).div_ceil(2);
+            for _ in 0..2 {
+                v = box_blur(v, w, h, r);
+            }
+        }
+
+        v.into_par_iter()
+            .map(|m| (m * 257.0).round().clamp(0.0, 65535.0) as u16)
+            .collect()
+    }
+}
+
+/// Box blur of radius `r` over a `width`x`height` plane, edges average fewer pixels
+pub fn box_blur(mut v: Vec<f32>, width: usize, height: usize, r: usize) -> Vec<f32> {
+    box_rows(&mut v, width, r);
+    let mut v = transpose(&v, width, height);
+    box_rows(&mut v, height, r);
+    transpose(&v, height, width)
+}
+
+// Box blur of radius `r` along every row, averaging only pixels inside the row
+fn box_rows(v: &mut [f32], width: usize, r: usize) {
+    v.par_chunks_exact_mut(width).for_each(|row| {
+        let mut prefix = Vec::with_capacity(width + 1);
+        prefix.push(0.0);
+        let mut sum = 0.0;
+        for &x in row.iter() {
+            sum += x;
+            prefix.push(sum);
+        }
+        for (x, out) in row.iter_mut().enumerate() {
+            let (lo, hi) = (x.saturating_sub(r), (x + r + 1).min(width));
+            *out = (prefix[hi] - prefix[lo]) / (hi - lo) as f32;
+        }
+    });
+}
+
+fn transpose(v: &[f32], width: usize, height: usize) -> Vec<f32> {
+    (0..width * height)
+        .into_par_iter()
+        .map(|i| v[(i % height) * width + i / height])
+        .collect()
+}
+
+/// Pulls `img` back towards `src` where `weights` are below full effect
+pub fn composite(img: &mut MilkBuffer, src: &MilkBuffer, weights: &[u16]) {
+    puffin::profile_function!();
+
+    img.as_mut()
+        .par_chunks_exact_mut(4)
+        .zip(src.as_raw().par_chunks_exact(4))
+        .zip(weights.par_iter())
+        .for_each(|((pixel, orig), &w)| {
+            let w = w as u64;
+            for c in 0..3 {
+                pixel[c] =
+                    ((pixel[c] as u64 * w + orig[c] as u64 * (65535 - w) + 32767) / 65535) as u16;
+            }
+        });
+}
+
+/// Kind of mask generated from the source image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Off,
+    /// Pixels inside a brightness range
+    Luma,
+    /// Pixels close to a hue and saturated enough
+    Color,
+    /// Straight ramp between two points
+    Linear,
+    /// Ramp growing outwards from a center
+    Radial,
+}
+
+impl KeyKind {
+    pub const ALL: [Self; 5] = [
+        Self::Off,
+        Self::Luma,
+        Self::Color,
+        Self::Linear,
+        Self::Radial,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Luma => "Luminance key",
+            Self::Color => "Color key",
+            Self::Linear => "Linear gradient",
+            Self::Radial => "Radial gradient",
+        }
+    }
+}
+
+/// Procedural effect mask, multiplied with the painted one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyMask {
+    pub kind: KeyKind,
+    pub invert: bool,
+    /// Brightness range of the luminance key, 0..=1
+    pub lo: f32,
+    pub hi: f32,
+    /// Hue of the color key in degrees and how far pixels may be from it
+    pub hue: f32,
+    pub tolerance: f32,
+    /// Least saturation the color key picks up, 0..=1
+    pub min_sat: f32,
+    /// Width of the key falloff, 0 is a hard edge
+    pub softness: f32,
+    /// Gradient start and end, or center and edge for radial, relative to image size
+    pub from: [f32; 2],
+    pub to: [f32; 2],
+}
+
+impl Default for KeyMask {
+    fn default() -> Self {
+        Self {
+            kind: KeyKind::Off,
+            invert: false,
+            lo: 0.0,
+            hi: 0.5,
+            hue: 0.0,
+            tolerance: 30.0,
+            min_sat: 0.2,
+            softness: 0.1,
+            from: [0.5, 0.5],
+            to: [1.0, 1.0],
+        }
+    }
+}
+
+impl KeyMask {
+    /// Per pixel effect weight in `0..=65535`, `None` when off
+    pub fn weights(&self, src: &MilkBuffer, luma: color::Luma) -> Option<Vec<u16>> {
+        puffin::profile_function!();
+
+        if self.kind == KeyKind::Off {
+            return None;
+        }
+        let lut = LumaLut::new(luma);
+        let (w, h) = (src.width() as usize, src.height() as usize);
+        let (sx, sy) = ((w.max(2) - 1) as f32, (h.max(2) - 1) as f32);
+        let soft = self.softness.max(1e-4);
+
+        let weights = src
+            .as_raw()
+            .par_chunks_exact(4)
+            .enumerate()
+            .map(|(i, p)| {
+                let (x, y) = ((i % w) as f32 / sx, (i / w) as f32 / sy);
+                let v = match self.kind {
+                    KeyKind::Luma => {
+                        let l = lut.index(p[0], p[1], p[2]) as f32 / LUMA_MAX as f32;
+                        ramp(self.lo - l, soft).min(ramp(l - self.hi, soft))
+                    }
+                    KeyKind::Color => {
+                        let (hue, sat) = hue_sat(p[0], p[1], p[2]);
+                        let d = (hue - self.hue).rem_euclid(360.0);
+                        let d = d.min(360.0 - d);
+                        ramp((d - self.tolerance) / 180.0, soft).min(ramp(self.min_sat - sat, soft))
+                    }
+                    KeyKind::Linear => {
+                        let (dx, dy) = (self.to[0] - self.from[0], self.to[1] - self.from[1]);
+                        let len = (dx * dx + dy * dy).max(1e-8);
+                        ((x - self.from[0]) * dx + (y - self.from[1]) * dy) / len
+                    }
+                    KeyKind::Radial => {
+                        let r = (self.to[0] - self.from[0]).hypot(self.to[1] - self.from[1]);
+                        (x - self.from[0]).hypot(y - self.from[1]) / r.max(1e-4)
+                    }
+                    KeyKind::Off => 1.0,
+                }
+                .clamp(0.0, 1.0);
+
+                let v = if self.invert { 1.0 - v } else { v };
+                (v * 65535.0).round() as u16
+            })
+            .collect();
+        Some(weights)
+    }
+}
+
+// 1 while `outside` <= 0, fading smoothly to 0 once `outside` reaches `soft`
+fn ramp(outside: f32, soft: f32) -> f32 {
+    let t = (1.0 - outside / soft).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// HSV hue in degrees and saturation of a 16 bit color
+fn hue_sat(r: u16, g: u16, b: u16) -> (f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta <= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let hue = if max == r {
+        (g - b) / delta
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    ((hue * 60.0).rem_euclid(360.0), delta / max)
+}
+
+/// Product of two optional weight maps
+pub fn combine(a: Option<Vec<u16>>, b: Option<Vec<u16>>) -> Option<Vec<u16>> {
+    match (a, b) {
+        (Some(mut a), Some(b)) => {
+            a.par_iter_mut()
+                .zip(b.par_iter())
+                .for_each(|(a, &b)| *a = ((*a as u32 * b as u32 + 32767) / 65535) as u16);
+            Some(a)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_and_strokes() {
+        let mut mask = Mask::new(16, 16, 0);
+        mask.fill_rect((4.0, 4.0), (8.0, 12.0), 255);
+        let painted = mask.as_image().pixels().filter(|p| p[0] == 255).count();
+        assert_eq!(painted, 4 * 8, "rectangle covers whole pixels");
+
+        let mut mask = Mask::new(16, 16, 0);
+        mask.fill_polygon(&[(0.0, 0.0), (16.0, 0.0), (0.0, 16.0)], 255);
+        let painted = mask.as_image().pixels().filter(|p| p[0] == 255).count();
+        assert!(
+            (120..=136).contains(&painted),
+            "half of the square, got {painted}"
+        );
+
+        let mut mask = Mask::new(16, 16, 0);
+        mask.stroke((2.0, 8.0), (14.0, 8.0), 1.0, 255);
+        assert_eq!(
+            mask.as_image().get_pixel(8, 7)[0],
+            255,
+            "brush follows the line"
+        );
+        assert_eq!(mask.as_image().get_pixel(8, 3)[0], 0, "brush stays thin");
+    }
+
+    #[test]
+    fn feather_softens_edges() {
+        let mut mask = Mask::new(32, 1, 0);
+        mask.fill_rect((16.0, 0.0), (32.0, 1.0), 255);
+
+        let hard = mask.weights(0);
+        assert_eq!((hard[15], hard[16]), (0, 65535));
+
+        let soft = mask.weights(8);
+        assert!(soft.windows(2).all(|w| w[0] <= w[1]), "ramp rises");
+        assert!(soft[15] > 0 && soft[16] < 65535, "edge is soft");
+        assert_eq!((soft[0], soft[31]), (0, 65535), "far pixels untouched");
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let mut mask = Mask::new(8, 4, 0);
+        mask.fill_rect((0.0, 0.0), (3.0, 4.0), 200);
+        let png = mask.to_png().expect("encodes");
+
+        assert_eq!(Mask::from_png(&png, 8, 4), Ok(mask));
+        assert_eq!(
+            Mask::from_png(&png, 4, 8),
+            Err(MaskError::Size {
+                expected: (4, 8),
+                found: (8, 4)
+            })
+        );
+    }
+
+    #[test]
+    fn keys_and_gradients() {
+        let src = MilkBuffer::from_fn(4, 1, |x, _| match x {
+            0 => image::Rgba([0, 0, 0, 65535]),
+            1 => image::Rgba([65535, 65535, 65535, 65535]),
+            2 => image::Rgba([60000, 5000, 5000, 65535]),
+            _ => image::Rgba([5000, 5000, 60000, 65535]),
+        });
+        let weights = |key: KeyMask| key.weights(&src, color::Luma::Average).expect("key is on");
+
+        let dark = KeyMask {
+            kind: KeyKind::Luma,
+            lo: 0.0,
+            hi: 0.2,
+            softness: 0.0,
+            ..KeyMask::default()
+        };
+        assert_eq!(weights(dark), [65535, 0, 0, 0]);
+
+        let red = KeyMask {
+            kind: KeyKind::Color,
+            hue: 0.0,
+            ..KeyMask::default()
+        };
+        assert_eq!(weights(red), [0, 0, 65535, 0]);
+        assert_eq!(
+            weights(KeyMask {
+                invert: true,
+                ..red
+            }),
+            [65535, 65535, 0, 65535]
+        );
+
+        let linear = KeyMask {
+            kind: KeyKind::Linear,
+            from: [0.0, 0.0],
+            to: [1.0, 0.0],
+            ..KeyMask::default()
+        };
+        assert_eq!(weights(linear), [0, 21845, 43690, 65535]);
+    }
+}
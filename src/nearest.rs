@@ -0,0 +1,169 @@
+use crate::color::{cielab, oklab};
+use crate::palette::Palette;
+use rayon::prelude::*;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+/// Grid points per channel of the nearest color LUT
+pub const LEVELS: usize = 64;
+
+/// How pixels are assigned palette colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapping {
+    /// By brightness, through the band table
+    Bands,
+    /// Perceptually nearest color in Oklab
+    Oklab,
+    /// Perceptually nearest color in CIELAB
+    Cielab,
+}
+
+impl Mapping {
+    pub const ALL: [Self; 3] = [Self::Bands, Self::Oklab, Self::Cielab];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bands => "Luminance bands",
+            Self::Oklab => "Nearest (Oklab)",
+            Self::Cielab => "Nearest (CIELAB)",
+        }
+    }
+
+    fn lab(self, r: u16, g: u16, b: u16) -> [f32; 3] {
+        match self {
+            Self::Cielab => cielab(r, g, b),
+            Self::Bands | Self::Oklab => oklab(r, g, b),
+        }
+    }
+}
+
+/// Two closest palette slots of one LUT cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nearest {
+    pub first: usize,
+    pub second: usize,
+    /// How far along the way from `first` to `second` the cell sits, 0..=0.5
+    pub share: f32,
+}
+
+/// LUT cell of a 16 bit color
+#[inline]
+pub fn cell(r: u16, g: u16, b: u16) -> usize {
+    let q = |c: u16| (c as usize * (LEVELS - 1) + 32767) / 65535;
+    (q(r) * LEVELS + q(g)) * LEVELS + q(b)
+}
+
+// Squared distance with the hue difference scaled by `hue_weight`
+fn distance(p: [f32; 3], q: [f32; 3], hue_weight: f32) -> f32 {
+    let (dl, da, db) = (p[0] - q[0], p[1] - q[1], p[2] - q[2]);
+    let dc = p[1].hypot(p[2]) - q[1].hypot(q[2]);
+    let dh = (da * da + db * db - dc * dc).max(0.0);
+    dl * dl + dc * dc + hue_weight * dh
+}
+
+/// Two nearest palette colors of every grid point, `hue_weight` of 1 is plain
+/// euclidean distance and larger values keep hues over lightness.
+pub fn build(palette: &Palette, mapping: Mapping, hue_weight: f32) -> Vec<Nearest> {
+    puffin::profile_function!();
+
+    let colors: Vec<[f32; 3]> = palette
+        .colors()
+        .iter()
+        .map(|c| mapping.lab(c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257))
+        .collect();
+    let level = |i: usize| (i * 65535 / (LEVELS - 1)) as u16;
+
+    (0..LEVELS * LEVELS * LEVELS)
+        .into_par_iter()
+        .map(|i| {
+            let p = mapping.lab(
+                level(i / (LEVELS * LEVELS)),
+                level(i / LEVELS % LEVELS),
+                level(i % LEVELS),
+            );
+
+            let (mut first, mut second) = ((0, f32::MAX), (0, f32::MAX));
+            for (slot, &c) in colors.iter().enumerate() {
+                let d = distance(p, c, hue_weight);
+                if d < first.1 {
+                    second = first;
+                    first = (slot, d);
+                } else if d < second.1 {
+                    second = (slot, d);
+                }
+            }
+
+            let (d1, d2) = (first.1.sqrt(), second.1.sqrt());
+            Nearest {
+                first: first.0,
+                second: second.0,
+                share: if d1 + d2 > 0.0 { d1 / (d1 + d2) } else { 0.0 },
+            }
+        })
+        .collect()
+}
+
+/// [`build`] that reuses the last table while palette, mapping and hue weight stay the same
+pub fn cached(palette: &Palette, mapping: Mapping, hue_weight: f32) -> Arc<Vec<Nearest>> {
+    type Entry = ((Palette, Mapping, u32), Arc<Vec<Nearest>>);
+    static CACHE: OnceLock<Mutex<Option<Entry>>> = OnceLock::new();
+
+    let key = (palette.clone(), mapping, hue_weight.to_bits());
+    let mut cache = CACHE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    match &*cache {
+        Some((k, lut)) if *k == key => lut.clone(),
+        _ => {
+            let lut = Arc::new(build(palette, mapping, hue_weight));
+            *cache = Some((key, lut.clone()));
+            lut
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_colors_map_to_themselves() {
+        let palette = Palette::new(vec![
+            (0, 0, 0),
+            (255, 255, 255),
+            (200, 30, 30),
+            (20, 60, 200),
+        ])
+        .expect("valid palette");
+        for mapping in [Mapping::Oklab, Mapping::Cielab] {
+            let lut = build(&palette, mapping, 1.0);
+            for (slot, c) in palette.colors().iter().enumerate() {
+                let n = lut[cell(c.0 as u16 * 257, c.1 as u16 * 257, c.2 as u16 * 257)];
+                assert_eq!(n.first, slot, "{mapping:?} {c:?}");
+                assert!(n.share < 0.1, "{mapping:?} {c:?} barely mixed");
+            }
+        }
+    }
+
+    #[test]
+    fn hue_weight_prefers_hue() {
+        // Orange is closer in lightness, dark red shares the hue
+        let palette = Palette::new(vec![(190, 110, 50), (110, 20, 20)]).expect("valid palette");
+        let red = cell(200 * 257, 60 * 257, 60 * 257);
+
+        assert_eq!(build(&palette, Mapping::Oklab, 1.0)[red].first, 0);
+        assert_eq!(build(&palette, Mapping::Oklab, 8.0)[red].first, 1);
+    }
+
+    #[test]
+    fn cache_follows_settings() {
+        let palette = Palette::milk();
+        let a = cached(&palette, Mapping::Cielab, 1.5);
+        assert!(
+            Arc::ptr_eq(&a, &cached(&palette, Mapping::Cielab, 1.5)),
+            "reused"
+        );
+        let b = cached(&palette, Mapping::Cielab, 2.0);
+        assert!(!Arc::ptr_eq(&a, &b), "rebuilt for another hue weight");
+    }
+}
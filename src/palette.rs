@@ -0,0 +1,221 @@
+use crate::color::{oklab, oklab_to_srgb};
+use rayon::prelude::*;
+use std::fmt;
+
+pub type Color = (u8, u8, u8);
+
+/// Smallest palette the band mapping can work with.
+pub const MIN_COLORS: usize = 2;
+/// Largest palette a user can build.
+pub const MAX_COLORS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteError {
+    TooFew(usize),
+    TooMany(usize),
+    /// Slot index past the end of the palette
+    NoSlot(usize),
+    /// Reference image has no opaque pixels
+    NoPixels,
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFew(n) => write!(f, "palette has {n} colors, at least {MIN_COLORS} needed"),
+            Self::TooMany(n) => write!(f, "palette has {n} colors, at most {MAX_COLORS} allowed"),
+            Self::NoSlot(i) => write!(f, "palette has no color {i}"),
+            Self::NoPixels => write!(f, "image has no opaque pixels to take colors from"),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+/// Colors ordered from darkest to lightest band.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<Color>,
+}
+
+impl Palette {
+    /// Creates palette from `colors`, which must hold `MIN_COLORS..=MAX_COLORS` entries
+    ///
+    /// # Errors
+    /// When there are too few or too many colors.
+    pub fn new(colors: Vec<Color>) -> Result<Self, PaletteError> {
+        Self::check_len(colors.len())?;
+        Ok(Self { colors })
+    }
+
+    /// Extracts `count` dominant colors of `img` with k-means in Oklab, darkest first
+    ///
+    /// # Errors
+    /// When `count` is out of range or `img` is fully transparent.
+    pub fn from_image(img: &image::RgbaImage, count: usize) -> Result<Self, PaletteError> {
+        puffin::profile_function!();
+
+        const MAX_SAMPLES: usize = 1 << 14;
+        const ITERATIONS: usize = 16;
+
+        Self::check_len(count)?;
+
+        let opaque = img.pixels().filter(|p| p[3] >= 128);
+        let step = (opaque.clone().count() / MAX_SAMPLES).max(1);
+        let samples: Vec<[f32; 3]> = opaque
+            .step_by(step)
+            .map(|p| oklab(p[0] as u16 * 257, p[1] as u16 * 257, p[2] as u16 * 257))
+            .collect();
+        if samples.is_empty() {
+            return Err(PaletteError::NoPixels);
+        }
+
+        let dist = |a: &[f32; 3], b: &[f32; 3]| {
+            (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+        };
+        let nearest = |centers: &[[f32; 3]], s: &[f32; 3]| {
+            (0..centers.len())
+                .min_by(|&i, &j| dist(&centers[i], s).total_cmp(&dist(&centers[j], s)))
+                .unwrap_or_default()
+        };
+
+        // Maximin seeding, starting from the darkest sample
+        let mut centers = vec![
+            *samples
+                .iter()
+                .min_by(|a, b| a[0].total_cmp(&b[0]))
+                .unwrap_or(&samples[0]),
+        ];
+        while centers.len() < count {
+            let far = samples
+                .par_iter()
+                .max_by(|a, b| {
+                    let da = dist(&centers[nearest(&centers, a)], a);
+                    let db = dist(&centers[nearest(&centers, b)], b);
+                    da.total_cmp(&db)
+                })
+                .copied()
+                .unwrap_or(samples[0]);
+            centers.push(far);
+        }
+
+        for _ in 0..ITERATIONS {
+            let sums = samples
+                .par_iter()
+                .fold(
+                    || vec![([0f32; 3], 0usize); count],
+                    |mut acc, s| {
+                        let (sum, n) = &mut acc[nearest(&centers, s)];
+                        for c in 0..3 {
+                            sum[c] += s[c];
+                        }
+                        *n += 1;
+                        acc
+                    },
+                )
+                .reduce(
+                    || vec![([0f32; 3], 0usize); count],
+                    |mut a, b| {
+                        for (x, y) in a.iter_mut().zip(b) {
+                            for c in 0..3 {
+                                x.0[c] += y.0[c];
+                            }
+                            x.1 += y.1;
+                        }
+                        a
+                    },
+                );
+
+            for (center, (sum, n)) in centers.iter_mut().zip(sums) {
+                if n > 0 {
+                    *center = sum.map(|v| v / n as f32);
+                }
+            }
+        }
+
+        centers.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        Self::new(centers.into_iter().map(oklab_to_srgb).collect())
+    }
+
+    /// Original milk palette
+    pub fn milk() -> Self {
+        Self {
+            colors: vec![(0, 0, 0), (102, 0, 31), (137, 0, 146)],
+        }
+    }
+
+    /// Alternative (redder) milk palette
+    pub fn alt() -> Self {
+        Self {
+            colors: vec![(0, 0, 0), (92, 36, 60), (203, 43, 43)],
+        }
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    pub fn colors_mut(&mut self) -> &mut [Color] {
+        &mut self.colors
+    }
+
+    pub fn push(&mut self, color: Color) -> Result<(), PaletteError> {
+        Self::check_len(self.colors.len() + 1)?;
+        self.colors.push(color);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<Color, PaletteError> {
+        if index >= self.colors.len() {
+            return Err(PaletteError::NoSlot(index));
+        }
+        Self::check_len(self.colors.len() - 1)?;
+        Ok(self.colors.remove(index))
+    }
+
+    fn check_len(len: usize) -> Result<(), PaletteError> {
+        if len < MIN_COLORS {
+            Err(PaletteError::TooFew(len))
+        } else if len > MAX_COLORS {
+            Err(PaletteError::TooMany(len))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_limits() {
+        let mut p = Palette::milk();
+        while p.colors().len() < MAX_COLORS {
+            assert!(p.push((1, 1, 1)).is_ok(), "up to 16 colors is valid");
+        }
+        assert_eq!(p.push((1, 1, 1)), Err(PaletteError::TooMany(17)));
+
+        let mut p = Palette::milk();
+        assert_eq!(p.remove(3), Err(PaletteError::NoSlot(3)));
+        assert!(p.remove(0).is_ok(), "3 -> 2 colors is valid");
+        assert_eq!(p.remove(0), Err(PaletteError::TooFew(1)));
+        assert_eq!(p.colors().len(), 2);
+    }
+
+    #[test]
+    fn derive_from_image() {
+        let img = image::RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 24 {
+                image::Rgba([200, 40, 40, 255])
+            } else {
+                image::Rgba([10, 10, 60, 255])
+            }
+        });
+        let p = Palette::from_image(&img, 2).expect("two colors");
+        assert_eq!(p.colors(), [(10, 10, 60), (200, 40, 40)]);
+
+        let clear = image::RgbaImage::new(4, 4);
+        assert_eq!(Palette::from_image(&clear, 2), Err(PaletteError::NoPixels));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::adjust::Tone;
+use crate::comp::{Blocks, Chroma, Generations, Jpeg, Quantize};
+use crate::effects::{Crt, Grain};
+use crate::filt::{Milk, MilkBuffer, MilkConfig};
+use crate::ink::Ink;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Effect parameter borrowed for editing.
+pub enum Param<'a> {
+    Bool(&'a mut bool),
+    U32(&'a mut u32, RangeInclusive<u32>),
+    F32(&'a mut f32, RangeInclusive<f32>),
+    /// Index into the labels, saved by label
+    Choice(&'a mut u32, &'static [&'static str]),
+}
+
+/// Current value of an effect parameter, read only counterpart of [`Param`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    U32(u32),
+    F32(f32),
+    /// Index into the labels, shown by label
+    Choice(u32, &'static [&'static str]),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::U32(v) => write!(f, "{v}"),
+            Self::F32(v) => write!(f, "{v}"),
+            Self::Choice(v, labels) => {
+                write!(
+                    f,
+                    "{}",
+                    labels.get(*v as usize).copied().unwrap_or_default()
+                )
+            }
+        }
+    }
+}
+
+impl Param<'_> {
+    // Parses and clamps `text` into the parameter, false when it is not a valid value
+    fn set(&mut self, text: &str) -> bool {
+        match self {
+            Self::Bool(v) => text.parse().map(|t| **v = t).is_ok(),
+            Self::U32(v, range) => text
+                .parse::<u32>()
+                .map(|t| **v = t.clamp(*range.start(), *range.end()))
+                .is_ok(),
+            Self::F32(v, range) => text
+                .parse::<f32>()
+                .map(|t| **v = t.clamp(*range.start(), *range.end()))
+                .is_ok(),
+            Self::Choice(v, labels) => labels
+                .iter()
+                .position(|&l| l == text)
+                .map(|i| **v = i as u32)
+                .is_some(),
+        }
+    }
+}
+
+/// One stage of the [`Pipeline`].
+pub trait Effect {
+    /// Stable identifier, also used when saving pipelines
+    fn name(&self) -> &'static str;
+
+    fn apply(&self, img: &mut MilkBuffer, conf: &MilkConfig);
+
+    /// Named parameters, names must not contain spaces or `=`
+    fn params(&mut self) -> Vec<(&'static str, Param<'_>)> {
+        Vec::new()
+    }
+
+    /// Compression level this stage applies, later stages may size themselves by it
+    fn compression(&self) -> Option<u32> {
+        None
+    }
+
+    /// Called before every run with the compression of the closest enabled stage before
+    /// this one, 0 when there is none
+    fn follow_compression(&mut self, _compression: u32) {}
+
+    /// Current parameter values, same names and order as [`Effect::params`]
+    fn values(&self) -> Vec<(&'static str, Value)> {
+        Vec::new()
+    }
+
+    /// Parameters as space separated `name=value` pairs
+    fn serialize(&self) -> String {
+        self.values()
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Reads parameters written by [`Effect::serialize`], missing ones are left as is
+    ///
+    /// # Errors
+    /// On unknown parameters or values that do not parse.
+    fn deserialize(&mut self, text: &str) -> Result<(), PipelineError> {
+        let effect = self.name();
+        let mut params = self.params();
+        for pair in text.split_whitespace() {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| PipelineError::Syntax(pair.to_owned()))?;
+            let (_, param) = params
+                .iter_mut()
+                .find(|(name, _)| *name == key)
+                .ok_or_else(|| PipelineError::UnknownParam {
+                    effect,
+                    param: key.to_owned(),
+                })?;
+            if !param.set(value) {
+                return Err(PipelineError::BadValue {
+                    param: key.to_owned(),
+                    value: value.to_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of all effects [`effect_by_name`] can create.
+pub const EFFECTS: [&str; 10] = [
+    "tone",
+    "jpeg",
+    "generations",
+    "chroma",
+    "quantize",
+    "blocks",
+    "milk",
+    "ink",
+    "grain",
+    "crt",
+];
+
+// Stages of a fresh pipeline
+const DEFAULT_STAGES: [&str; 4] = ["tone", "jpeg", "blocks", "milk"];
+
+/// Creates effect with default parameters
+pub fn effect_by_name(name: &str) -> Option<Box<dyn Effect>> {
+    match name {
+        "tone" => Some(Box::new(Tone::default())),
+        "jpeg" => Some(Box::new(Jpeg::default())),
+        "generations" => Some(Box::new(Generations::default())),
+        "chroma" => Some(Box::new(Chroma::default())),
+        "quantize" => Some(Box::new(Quantize::default())),
+        "blocks" => Some(Box::new(Blocks::default())),
+        "milk" => Some(Box::new(Milk)),
+        "ink" => Some(Box::new(Ink::default())),
+        "grain" => Some(Box::new(Grain::default())),
+        "crt" => Some(Box::new(Crt::default())),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineError {
+    UnknownEffect(String),
+    UnknownParam {
+        effect: &'static str,
+        param: String,
+    },
+    BadValue {
+        param: String,
+        value: String,
+    },
+    /// Neither `name=value` nor an on/off flag
+    Syntax(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownEffect(name) => write!(f, "unknown effect {name}"),
+            Self::UnknownParam { effect, param } => {
+                write!(f, "effect {effect} has no parameter {param}")
+            }
+            Self::BadValue { param, value } => write!(f, "{value} is not valid for {param}"),
+            Self::Syntax(text) => write!(f, "cannot read {text}"),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+pub struct Stage {
+    pub effect: Box<dyn Effect>,
+    pub enabled: bool,
+}
+
+/// Effects applied to the image in order.
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    /// Classic order, tone and compression first and milk last
+    pub fn new() -> Self {
+        Self {
+            stages: DEFAULT_STAGES
+                .iter()
+                .filter_map(|name| effect_by_name(name))
+                .map(|effect| Stage {
+                    effect,
+                    enabled: true,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Stages for editing and reordering
+    pub fn stages_mut(&mut self) -> &mut [Stage] {
+        &mut self.stages
+    }
+
+    pub fn push(&mut self, effect: Box<dyn Effect>) {
+        self.stages.push(Stage {
+            effect,
+            enabled: true,
+        });
+    }
+
+    pub fn remove(&mut self, index: usize) -> Stage {
+        self.stages.remove(index)
+    }
+
+    pub fn run(&mut self, img: &mut MilkBuffer, conf: &MilkConfig) {
+        puffin::profile_function!();
+
+        let mut compression = 0;
+        for stage in self.stages.iter_mut().filter(|s| s.enabled) {
+            puffin::profile_scope!("s_effect", stage.effect.name());
+            stage.effect.follow_compression(compression);
+            stage.effect.apply(img, conf);
+            compression = stage.effect.compression().unwrap_or(compression);
+        }
+    }
+
+    /// One line per stage: name, `on` or `off`, then parameters
+    pub fn serialize(&self) -> String {
+        self.stages
+            .iter()
+            .map(|s| {
+                let flag = if s.enabled { "on" } else { "off" };
+                format!("{} {flag} {}", s.effect.name(), s.effect.serialize())
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Reads pipeline written by [`Pipeline::serialize`]
+    ///
+    /// # Errors
+    /// On unknown effects or parameters and malformed lines.
+    pub fn deserialize(text: &str) -> Result<Self, PipelineError> {
+        let mut stages = Vec::new();
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let mut effect = effect_by_name(name)
+                .ok_or_else(|| PipelineError::UnknownEffect(name.to_owned()))?;
+
+            let (flag, params) = rest.trim_start().split_once(' ').unwrap_or((rest, ""));
+            let enabled = match flag.trim() {
+                "on" => true,
+                "off" => false,
+                other => return Err(PipelineError::Syntax(other.to_owned())),
+            };
+            effect.deserialize(params)?;
+            stages.push(Stage { effect, enabled });
+        }
+        Ok(Self { stages })
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_match_params() {
+        for name in EFFECTS {
+            let mut effect = effect_by_name(name).expect("listed effect");
+            let values: Vec<_> = effect.values().iter().map(|(n, _)| *n).collect();
+            let params: Vec<_> = effect.params().iter().map(|(n, _)| *n).collect();
+            assert_eq!(values, params, "{name}");
+        }
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut pipeline = Pipeline::new();
+        pipeline.stages_mut().swap(1, 3);
+        pipeline.stages_mut()[2].enabled = false;
+        let mut params = pipeline.stages_mut()[2].effect.params();
+        if let Some((_, Param::U32(size, _))) = params.iter_mut().find(|(n, _)| *n == "size") {
+            **size = 8;
+        }
+        drop(params);
+        drop(pipeline.remove(0));
+
+        let text = pipeline.serialize();
+        assert_eq!(
+            text,
+            "milk on\nblocks off size=8 adaptive=false min_size=2 threshold=0.05\njpeg on compression=0"
+        );
+        let loaded = Pipeline::deserialize(&text).expect("valid pipeline");
+        assert_eq!(loaded.serialize(), text);
+    }
+
+    #[test]
+    fn choices_save_by_label() {
+        let text = "chroma on scheme=4:1:1 bilinear=false";
+        let pipeline = Pipeline::deserialize(text).expect("valid pipeline");
+        assert_eq!(pipeline.serialize(), text);
+        assert!(
+            Pipeline::deserialize("chroma on scheme=2").is_err(),
+            "indices are not labels"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_text() {
+        assert_eq!(
+            Pipeline::deserialize("sharpen on").err(),
+            Some(PipelineError::UnknownEffect("sharpen".to_owned()))
+        );
+        assert_eq!(
+            Pipeline::deserialize("blocks on radius=2").err(),
+            Some(PipelineError::UnknownParam {
+                effect: "blocks",
+                param: "radius".to_owned()
+            })
+        );
+        assert_eq!(
+            Pipeline::deserialize("blocks maybe").err(),
+            Some(PipelineError::Syntax("maybe".to_owned()))
+        );
+        assert!(
+            Pipeline::deserialize("blocks on size=big").is_err(),
+            "size must be a number"
+        );
+    }
+}